@@ -0,0 +1,273 @@
+use std::fmt;
+
+use clap::{Subcommand, ValueEnum};
+
+use crate::datalogger::{WriteError, DataLogger};
+use crate::parser::types::*;
+
+/// Storage working modes accepted by `working_mode_setting`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum WorkingMode {
+    Adaptive,
+    FixedChargeDischarge,
+    MaximiseSelfConsumption,
+    TimeOfUseLg,
+    FullyFedToGrid,
+    TimeOfUseLuna2000,
+}
+
+impl WorkingMode {
+    fn code(&self) -> f64 {
+        match self {
+            WorkingMode::Adaptive => 0.0,
+            WorkingMode::FixedChargeDischarge => 1.0,
+            WorkingMode::MaximiseSelfConsumption => 2.0,
+            WorkingMode::TimeOfUseLg => 3.0,
+            WorkingMode::FullyFedToGrid => 4.0,
+            WorkingMode::TimeOfUseLuna2000 => 5.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Subcommand)]
+pub enum BatteryCommand {
+    /// Charge at a fixed power for a while, regardless of the working mode
+    ForceCharge {
+        /// Watts, at most the rated charge power
+        #[arg(long)]
+        power: f64,
+        #[arg(long, default_value_t = 60)]
+        minutes: u16,
+    },
+    /// Discharge at a fixed power for a while, regardless of the working mode
+    ForceDischarge {
+        /// Watts, at most the rated discharge power
+        #[arg(long)]
+        power: f64,
+        #[arg(long, default_value_t = 60)]
+        minutes: u16,
+    },
+    /// End a forced charge or discharge
+    Stop,
+    /// Set the storage working mode
+    Mode {
+        mode: WorkingMode,
+    },
+    /// Limit the charge power in watts
+    MaxChargePower {
+        power: f64,
+    },
+    /// Limit the discharge power in watts
+    MaxDischargePower {
+        power: f64,
+    },
+    /// Charge from the grid up to this SOC in percent, or `off`
+    GridChargeCutoff {
+        /// Percent, 20 to 100
+        #[arg(required_unless_present = "off")]
+        soc: Option<f64>,
+        /// Disable charging from the grid instead
+        #[arg(long, conflicts_with = "soc")]
+        off: bool,
+    },
+}
+
+/// Rated powers of the battery as read from the inverter
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RatedPower {
+    pub charge: f64,
+    pub discharge: f64,
+}
+
+/// A command that failed part way, the inverter took `written` before `error`
+#[derive(Debug)]
+pub struct CommandError {
+    pub written: Vec<PVSignal>,
+    pub error: WriteError,
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.written.len() {
+            0 => write!(f, "{}", self.error),
+            _ => {
+                let names: Vec<&str> = self.written.iter().map(|s| s.name.as_str()).collect();
+                write!(f, "{} after writing {}", self.error, names.join(", "))
+            }
+        }
+    }
+}
+
+impl From<WriteError> for CommandError {
+    fn from(error: WriteError) -> Self {
+        CommandError { written: Vec::new(), error }
+    }
+}
+
+impl BatteryCommand {
+    fn needs_rated_power(&self) -> bool {
+        matches!(self, BatteryCommand::ForceCharge { .. } | BatteryCommand::ForceDischarge { .. } | BatteryCommand::MaxChargePower { .. } | BatteryCommand::MaxDischargePower { .. })
+    }
+
+    /// Writes that carry out the command, in order. The forcible command has
+    /// to come last so the inverter picks up the period and power with it.
+    pub fn writes(&self, rated: Option<RatedPower>) -> Result<Vec<(&'static str, f64)>, WriteError> {
+        let check = |power: f64, max: Option<f64>, what: &str| match max {
+            _ if power.is_nan() || power <= 0.0 => Err(WriteError::Invalid(format!("the {} power has to be more than 0 W, got {}", what, power))),
+            Some(max) if power > max => Err(WriteError::Invalid(format!("{} W exceeds the rated {} power of {} W", power, what, max))),
+            _ => Ok(()),
+        };
+        let period = |minutes: u16| match minutes {
+            0 => Err(WriteError::Invalid("the period has to be at least a minute".to_string())),
+            _ => Ok(minutes as f64),
+        };
+        Ok(match *self {
+            BatteryCommand::ForceCharge { power, minutes } => {
+                check(power, rated.map(|r| r.charge), "charge")?;
+                vec![("forcible_period", period(minutes)?), ("forcible_charge_power", power), ("forcible_command", 1.0)]
+            }
+            BatteryCommand::ForceDischarge { power, minutes } => {
+                check(power, rated.map(|r| r.discharge), "discharge")?;
+                vec![("forcible_period", period(minutes)?), ("forcible_discharge_power", power), ("forcible_command", 2.0)]
+            }
+            BatteryCommand::Stop => vec![("forcible_command", 0.0)],
+            BatteryCommand::Mode { mode } => vec![("working_mode_setting", mode.code())],
+            BatteryCommand::MaxChargePower { power } => {
+                check(power, rated.map(|r| r.charge), "charge")?;
+                vec![("charge_power_limit", power)]
+            }
+            BatteryCommand::MaxDischargePower { power } => {
+                check(power, rated.map(|r| r.discharge), "discharge")?;
+                vec![("discharge_power_limit", power)]
+            }
+            BatteryCommand::GridChargeCutoff { soc: Some(soc), .. } => vec![("grid_charge", 1.0), ("grid_charge_cutoff_soc", soc)],
+            BatteryCommand::GridChargeCutoff { soc: None, .. } => vec![("grid_charge", 0.0)],
+        })
+    }
+}
+
+fn watts(signal: &PVSignal) -> Result<f64, WriteError> {
+    signal.scaled().ok_or_else(|| WriteError::Invalid(format!("{} is not a number", signal.name)))
+}
+
+/// Reads the rated powers of the battery, zero means there is none
+pub async fn read_rated_power(datalogger: &mut DataLogger) -> Result<RatedPower, WriteError> {
+    let rated = RatedPower {
+        charge: watts(&datalogger.read_signal("rated_charge_power").await?)?,
+        discharge: watts(&datalogger.read_signal("rated_discharge_power").await?)?,
+    };
+    if rated.charge == 0.0 && rated.discharge == 0.0 {
+        return Err(WriteError::Invalid("no battery connected".to_string()));
+    }
+    Ok(rated)
+}
+
+/// Checks the command against the battery and writes it, stopping at the
+/// first failed write. Nothing is rolled back, the error lists what was written.
+pub async fn execute(datalogger: &mut DataLogger, command: &BatteryCommand) -> Result<Vec<PVSignal>, CommandError> {
+    let rated = match command.needs_rated_power() {
+        true => Some(read_rated_power(datalogger).await?),
+        false => None,
+    };
+    let mut written = Vec::new();
+    for (name, value) in command.writes(rated)? {
+        match datalogger.write_signal(name, value).await {
+            Ok(signal) => written.push(signal),
+            Err(error) => return Err(CommandError { written, error }),
+        }
+    }
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATED: Option<RatedPower> = Some(RatedPower { charge: 5000.0, discharge: 4500.0 });
+
+    #[test]
+    fn forced_charge_sets_period_and_power_first() {
+        let writes = BatteryCommand::ForceCharge { power: 2500.0, minutes: 30 }.writes(RATED).unwrap();
+        assert_eq!(writes, vec![("forcible_period", 30.0), ("forcible_charge_power", 2500.0), ("forcible_command", 1.0)]);
+        let writes = BatteryCommand::ForceDischarge { power: 4500.0, minutes: 60 }.writes(RATED).unwrap();
+        assert_eq!(writes.last(), Some(&("forcible_command", 2.0)));
+    }
+
+    #[test]
+    fn rejects_more_than_rated_power() {
+        assert!(matches!(BatteryCommand::ForceDischarge { power: 4501.0, minutes: 60 }.writes(RATED), Err(WriteError::Invalid(_))));
+        assert!(matches!(BatteryCommand::MaxChargePower { power: 6000.0 }.writes(RATED), Err(WriteError::Invalid(_))));
+        assert!(BatteryCommand::MaxChargePower { power: 5000.0 }.writes(RATED).is_ok());
+    }
+
+    #[test]
+    fn rejects_nothing_to_do() {
+        let rejected = [
+            BatteryCommand::ForceCharge { power: 0.0, minutes: 30 },
+            BatteryCommand::ForceDischarge { power: -100.0, minutes: 30 },
+            BatteryCommand::ForceCharge { power: 1000.0, minutes: 0 },
+            BatteryCommand::MaxDischargePower { power: f64::NAN },
+        ];
+        for c in rejected {
+            assert!(matches!(c.writes(RATED), Err(WriteError::Invalid(_))), "{:?}", c);
+        }
+        assert!(matches!(BatteryCommand::MaxChargePower { power: -1.0 }.writes(None), Err(WriteError::Invalid(_))));
+    }
+
+    #[tokio::test]
+    async fn reports_the_writes_before_a_failure() {
+        use std::time::Duration;
+        use tokio::net::TcpListener;
+        use tokio_modbus::prelude::Slave;
+        use crate::connection::{Connection, Transport};
+        use crate::planner::ReadPlanner;
+        use crate::profiles::Registry;
+        use crate::schedule::Schedule;
+        use crate::simulator::{Simulator, SimulatorConfig};
+
+        let defs = crate::parser::read_definitions(crate::parser::DEFAULT_DEFINITIONS).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let connection = Connection::new(Transport::Tcp(listener.local_addr().unwrap()), Slave(1), Duration::from_millis(500));
+        // forcible_command
+        let config = SimulatorConfig { packs: vec![0], exceptions: vec![47100], ..Default::default() };
+        tokio::spawn(Simulator::new(&defs, 1, config).unwrap().serve(listener));
+        let profiles = Registry::load(crate::parser::DEFAULT_DEFINITIONS).unwrap();
+        let schedule = Schedule::new(profiles.base(), Duration::from_secs(90)).unwrap();
+        let mut datalogger = DataLogger::new(connection, profiles, ReadPlanner::default(), schedule, 10, Duration::ZERO);
+
+        let err = execute(&mut datalogger, &BatteryCommand::ForceCharge { power: 2500.0, minutes: 30 }).await.unwrap_err();
+        let written: Vec<&str> = err.written.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(written, vec!["forcible_period", "forcible_charge_power"]);
+        assert!(matches!(err.error, WriteError::Modbus(_)));
+        assert!(err.to_string().ends_with("after writing forcible_period, forcible_charge_power"), "{}", err);
+    }
+
+    #[test]
+    fn writes_defined_signals() {
+        let defs = crate::parser::read_definitions(crate::parser::DEFAULT_DEFINITIONS).unwrap();
+        let commands = [
+            BatteryCommand::ForceCharge { power: 1.0, minutes: 1 },
+            BatteryCommand::ForceDischarge { power: 1.0, minutes: 1 },
+            BatteryCommand::Stop,
+            BatteryCommand::Mode { mode: WorkingMode::TimeOfUseLuna2000 },
+            BatteryCommand::MaxChargePower { power: 1.0 },
+            BatteryCommand::MaxDischargePower { power: 1.0 },
+            BatteryCommand::GridChargeCutoff { soc: Some(50.0), off: false },
+        ];
+        for c in commands {
+            for (name, value) in c.writes(RATED).unwrap() {
+                let def = defs.const_field.iter().find(|d| d.name == name).unwrap();
+                assert!(def.writable, "{}", name);
+                assert!(def.min.is_none_or(|min| value >= min) && def.max.is_none_or(|max| value <= max), "{} = {}", name, value);
+            }
+        }
+    }
+
+    #[test]
+    fn settings() {
+        assert_eq!(BatteryCommand::Mode { mode: WorkingMode::MaximiseSelfConsumption }.writes(None).unwrap(), vec![("working_mode_setting", 2.0)]);
+        assert_eq!(BatteryCommand::GridChargeCutoff { soc: Some(80.0), off: false }.writes(None).unwrap(), vec![("grid_charge", 1.0), ("grid_charge_cutoff_soc", 80.0)]);
+        assert_eq!(BatteryCommand::GridChargeCutoff { soc: None, off: true }.writes(None).unwrap(), vec![("grid_charge", 0.0)]);
+        assert!(!BatteryCommand::Stop.needs_rated_power());
+    }
+}
@@ -0,0 +1,312 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io::{self, LineWriter, Write};
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use serde_derive::{Deserialize, Serialize};
+use tokio_modbus::client::Client;
+use tokio_modbus::prelude::*;
+
+use crate::connection::{ConnectionState, ConnectionStats, ModbusError};
+use crate::source::RegisterSource;
+
+/// Function codes of the requests we send
+pub const READ_HOLDING_REGISTERS: u8 = 0x03;
+pub const READ_INPUT_REGISTERS: u8 = 0x04;
+const WRITE_SINGLE_REGISTER: u8 = 0x06;
+const WRITE_MULTIPLE_REGISTERS: u8 = 0x10;
+
+/// One Modbus request and what came back, a line of a capture file
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Exchange {
+    /// Unix millis of the response
+    pub time: i64,
+    pub unit: u8,
+    pub function: u8,
+    pub address: u16,
+    pub count: u16,
+    /// Registers read or written
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub words: Vec<u16>,
+    /// Exception the inverter answered with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exception: Option<String>,
+    /// Transport problem, no answer at all
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Exchange {
+    fn new(unit: u8, function: u8, address: u16, count: u16, words: Vec<u16>, err: Option<&ModbusError>) -> Exchange {
+        let (exception, error) = match err {
+            Some(ModbusError::Exception(e)) => (Some(e.clone()), None),
+            Some(e) => (None, Some(e.to_string())),
+            None => (None, None),
+        };
+        Exchange { time: chrono::Utc::now().timestamp_millis(), unit, function, address, count, words, exception, error }
+    }
+
+    pub fn read(unit: u8, function: u8, address: u16, count: u16, res: &Result<Vec<u16>, ModbusError>) -> Exchange {
+        match res {
+            Ok(words) => Exchange::new(unit, function, address, count, words.clone(), None),
+            Err(e) => Exchange::new(unit, function, address, count, Vec::new(), Some(e)),
+        }
+    }
+
+    pub fn write(unit: u8, address: u16, data: &[u16], res: &Result<(), ModbusError>) -> Exchange {
+        let function = if data.len() == 1 { WRITE_SINGLE_REGISTER } else { WRITE_MULTIPLE_REGISTERS };
+        Exchange::new(unit, function, address, data.len() as u16, data.to_vec(), res.as_ref().err())
+    }
+}
+
+/// Appends every exchange to a JSON lines file. Clones write to the same
+/// file, so all devices can share one.
+#[derive(Debug, Clone)]
+pub struct Capture {
+    path: String,
+    file: Arc<Mutex<LineWriter<File>>>,
+}
+
+impl Capture {
+    pub fn open(path: &str) -> io::Result<Capture> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Capture { path: path.to_string(), file: Arc::new(Mutex::new(LineWriter::new(file))) })
+    }
+
+    /// Failing to write the capture never stops the logger
+    pub fn record(&self, exchange: &Exchange) {
+        let line = serde_json::to_string(exchange).expect("Exchanges always serialize");
+        if let Err(e) = writeln!(self.file.lock().unwrap(), "{}", line) {
+            warn!("Unable to write to the capture {}: {}", self.path, e);
+        }
+    }
+}
+
+/// Answers from a capture, as a register source or as the Modbus client
+/// of a connection. Reads get the responses captured for the same request
+/// in order, repeating the last one once they run out. Reads that were
+/// never captured as such are put together from the last captured value
+/// of each register. Writes are accepted.
+#[derive(Debug)]
+pub struct Replay {
+    slave: SlaveId,
+    /// Keyed by unit, function, address and count
+    reads: HashMap<(u8, u8, u16, u16), VecDeque<Exchange>>,
+    /// Keyed by unit, read function and address
+    registers: HashMap<(u8, u8, u16), u16>,
+    stats: ConnectionStats,
+}
+
+fn exception(function: u8, description: &str) -> io::Error {
+    // formatted like the exceptions of tokio-modbus, see `ModbusError::from`
+    io::Error::other(format!("Modbus function {}: {}", function, description))
+}
+
+/// Addresses of `count` registers from `address`, `None` if they run past
+/// the end of the address space
+fn addresses(address: u16, count: usize) -> Option<impl Iterator<Item = u16>> {
+    let count = u16::try_from(count).ok()?;
+    address.checked_add(count.saturating_sub(1))?;
+    Some((0..count).map(move |i| address + i))
+}
+
+impl Replay {
+    pub fn parse(content: &str) -> Result<Replay, String> {
+        let mut replay = Replay { slave: 0, reads: HashMap::new(), registers: HashMap::new(), stats: ConnectionStats::default() };
+        for (i, line) in content.lines().enumerate().filter(|(_, l)| !l.trim().is_empty()) {
+            let exchange: Exchange = serde_json::from_str(line).map_err(|e| format!("line {}: {}", i + 1, e))?;
+            // without an answer there is nothing to replay
+            if exchange.error.is_some() {
+                continue;
+            }
+            // writes end up in the holding registers
+            let space = match exchange.function {
+                READ_INPUT_REGISTERS => READ_INPUT_REGISTERS,
+                _ => READ_HOLDING_REGISTERS,
+            };
+            let count = (exchange.count as usize).max(exchange.words.len());
+            let addresses = addresses(exchange.address, count).ok_or_else(|| format!("line {}: {} registers from {} run past the last address", i + 1, count, exchange.address))?;
+            for (a, w) in addresses.zip(exchange.words.iter()) {
+                replay.registers.insert((exchange.unit, space, a), *w);
+            }
+            if exchange.function == READ_HOLDING_REGISTERS || exchange.function == READ_INPUT_REGISTERS {
+                replay.reads.entry((exchange.unit, exchange.function, exchange.address, exchange.count)).or_default().push_back(exchange);
+            }
+        }
+        Ok(replay)
+    }
+
+    pub async fn load(path: &str) -> io::Result<Replay> {
+        let content = tokio::fs::read_to_string(path).await?;
+        Replay::parse(&content).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("invalid capture {}: {}", path, e)))
+    }
+
+    fn read(&mut self, function: u8, address: u16, count: u16) -> io::Result<Vec<u16>> {
+        if let Some(captured) = self.reads.get_mut(&(self.slave, function, address, count)) {
+            let exchange = match captured.len() {
+                1 => captured[0].clone(),
+                _ => captured.pop_front().unwrap(),
+            };
+            return match exchange.exception {
+                Some(e) => Err(io::Error::other(e)),
+                None => Ok(exchange.words),
+            };
+        }
+        addresses(address, count as usize)
+            .and_then(|mut addresses| addresses.try_fold(Vec::new(), |mut words, a| {
+                words.push(*self.registers.get(&(self.slave, function, a))?);
+                Some(words)
+            }))
+            .ok_or_else(|| exception(function, "Illegal data address"))
+    }
+
+    fn write(&mut self, function: u8, address: u16, words: &[u16]) -> io::Result<()> {
+        let addresses = addresses(address, words.len()).ok_or_else(|| exception(function, "Illegal data address"))?;
+        for (a, w) in addresses.zip(words) {
+            self.registers.insert((self.slave, READ_HOLDING_REGISTERS, a), *w);
+        }
+        Ok(())
+    }
+}
+
+impl SlaveContext for Replay {
+    fn set_slave(&mut self, slave: Slave) {
+        self.slave = slave.0;
+    }
+}
+
+#[async_trait]
+impl Client for Replay {
+    async fn call(&mut self, request: Request<'_>) -> io::Result<Response> {
+        match request {
+            Request::ReadHoldingRegisters(address, count) => self.read(READ_HOLDING_REGISTERS, address, count).map(Response::ReadHoldingRegisters),
+            Request::ReadInputRegisters(address, count) => self.read(READ_INPUT_REGISTERS, address, count).map(Response::ReadInputRegisters),
+            Request::WriteSingleRegister(address, word) => {
+                self.write(WRITE_SINGLE_REGISTER, address, &[word])?;
+                Ok(Response::WriteSingleRegister(address, word))
+            }
+            Request::WriteMultipleRegisters(address, words) => {
+                self.write(WRITE_MULTIPLE_REGISTERS, address, &words)?;
+                Ok(Response::WriteMultipleRegisters(address, words.len() as u16))
+            }
+            _ => Err(io::Error::new(io::ErrorKind::Unsupported, "only register reads and writes can be replayed")),
+        }
+    }
+}
+
+impl Replay {
+    fn count(&mut self, res: io::Result<Vec<u16>>) -> Result<Vec<u16>, ModbusError> {
+        self.stats.requests += 1;
+        let res = res.map_err(ModbusError::from);
+        if res.is_err() {
+            self.stats.exceptions += 1;
+        }
+        res
+    }
+}
+
+#[async_trait]
+impl RegisterSource for Replay {
+    async fn read_holding_registers(&mut self, address: u16, count: u16) -> Result<Vec<u16>, ModbusError> {
+        let res = self.read(READ_HOLDING_REGISTERS, address, count);
+        self.count(res)
+    }
+
+    async fn read_input_registers(&mut self, address: u16, count: u16) -> Result<Vec<u16>, ModbusError> {
+        let res = self.read(READ_INPUT_REGISTERS, address, count);
+        self.count(res)
+    }
+
+    async fn write_registers(&mut self, address: u16, data: &[u16]) -> Result<(), ModbusError> {
+        self.stats.requests += 1;
+        self.write(WRITE_MULTIPLE_REGISTERS, address, data).map_err(|e| {
+            self.stats.exceptions += 1;
+            ModbusError::from(e)
+        })
+    }
+
+    fn state(&self) -> ConnectionState {
+        ConnectionState::Connected
+    }
+
+    fn stats(&self) -> &ConnectionStats {
+        &self.stats
+    }
+
+    fn skip_cycle(&mut self) {
+        self.stats.skipped_cycles += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn capture(exchanges: &[Exchange]) -> String {
+        exchanges.iter().map(|e| serde_json::to_string(e).unwrap() + "\n").collect()
+    }
+
+    fn replay(exchanges: &[Exchange]) -> Replay {
+        let mut replay = Replay::parse(&capture(exchanges)).unwrap();
+        replay.set_slave(Slave(1));
+        replay
+    }
+
+    #[test]
+    fn writes_json_lines() {
+        let read = Exchange { time: 1, ..Exchange::read(1, READ_HOLDING_REGISTERS, 32080, 2, &Ok(vec![0, 7711])) };
+        assert_eq!(serde_json::to_string(&read).unwrap(), r#"{"time":1,"unit":1,"function":3,"address":32080,"count":2,"words":[0,7711]}"#);
+        let failed = Exchange::read(1, READ_HOLDING_REGISTERS, 32080, 2, &Err(ModbusError::Exception("Modbus function 3: Illegal data address".to_string())));
+        assert_eq!(failed.exception.as_deref(), Some("Modbus function 3: Illegal data address"));
+        let written = Exchange::write(2, 40125, &[505], &Ok(()));
+        assert_eq!((written.function, written.count, written.words), (WRITE_SINGLE_REGISTER, 1, vec![505]));
+        assert!(Replay::parse("{\"time\":1}\n").is_err());
+    }
+
+    #[test]
+    fn replays_in_order() {
+        let mut replay = replay(&[
+            Exchange::read(1, READ_HOLDING_REGISTERS, 30071, 1, &Ok(vec![2])),
+            Exchange::read(1, READ_HOLDING_REGISTERS, 32080, 2, &Ok(vec![0, 100])),
+            Exchange::read(1, READ_HOLDING_REGISTERS, 32080, 2, &Err(ModbusError::Io(io::Error::new(io::ErrorKind::TimedOut, "request timed out")))),
+            Exchange::read(1, READ_HOLDING_REGISTERS, 32080, 2, &Ok(vec![0, 200])),
+        ]);
+        assert_eq!(replay.read(READ_HOLDING_REGISTERS, 32080, 2).unwrap(), vec![0, 100]);
+        assert_eq!(replay.read(READ_HOLDING_REGISTERS, 32080, 2).unwrap(), vec![0, 200]);
+        // the last response is repeated
+        assert_eq!(replay.read(READ_HOLDING_REGISTERS, 32080, 2).unwrap(), vec![0, 200]);
+        // other slaves weren't captured
+        replay.set_slave(Slave(2));
+        assert!(matches!(replay.read(READ_HOLDING_REGISTERS, 30071, 1).map_err(ModbusError::from), Err(ModbusError::Exception(_))));
+    }
+
+    #[test]
+    fn puts_together_reads_planned_differently() {
+        let mut replay = replay(&[
+            Exchange::read(1, READ_HOLDING_REGISTERS, 32064, 4, &Ok(vec![0, 8000, 2300, 2310])),
+            Exchange::read(1, READ_HOLDING_REGISTERS, 32080, 2, &Ok(vec![0, 7760])),
+        ]);
+        assert_eq!(replay.read(READ_HOLDING_REGISTERS, 32066, 2).unwrap(), vec![2300, 2310]);
+        assert!(replay.read(READ_HOLDING_REGISTERS, 32066, 16).is_err());
+        replay.write(WRITE_SINGLE_REGISTER, 40125, &[505]).unwrap();
+        assert_eq!(replay.read(READ_HOLDING_REGISTERS, 40125, 1).unwrap(), vec![505]);
+    }
+
+    #[test]
+    fn rejects_addresses_past_the_end() {
+        let line = r#"{"time":1,"unit":1,"function":3,"address":65535,"count":2,"words":[1,2]}"#;
+        assert_eq!(Replay::parse(&format!("\n{}\n", line)).err().as_deref(), Some("line 2: 2 registers from 65535 run past the last address"));
+        let mut replay = replay(&[Exchange::read(1, READ_HOLDING_REGISTERS, 65535, 1, &Ok(vec![7]))]);
+        assert_eq!(replay.read(READ_HOLDING_REGISTERS, 65535, 1).unwrap(), vec![7]);
+        assert!(replay.read(READ_HOLDING_REGISTERS, 65535, 2).is_err());
+        assert!(replay.write(WRITE_MULTIPLE_REGISTERS, 65535, &[1, 2]).is_err());
+    }
+
+    #[test]
+    fn replays_exceptions() {
+        let err = ModbusError::Exception("Modbus function 3: Illegal data address".to_string());
+        let mut replay = replay(&[Exchange::read(1, READ_HOLDING_REGISTERS, 37700, 10, &Err(err))]);
+        assert!(matches!(replay.read(READ_HOLDING_REGISTERS, 37700, 10).map_err(ModbusError::from), Err(ModbusError::Exception(_))));
+    }
+}
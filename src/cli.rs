@@ -0,0 +1,501 @@
+use std::path::Path;
+use std::process;
+use std::time::{Duration, Instant};
+
+use clap::{Parser, Subcommand};
+use tokio::net::TcpListener;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tokio::time;
+use tokio_modbus::prelude::Slave;
+
+use crate::battery::{self, BatteryCommand};
+use crate::capture::Capture;
+use crate::config::{Config, Device, ExportLimitConfig, InverterConfig};
+use crate::connection::{Connection, ModbusError, Pacer};
+use crate::datalogger::DataLogger;
+use crate::export_limit::ExportLimiter;
+use crate::parser::{self, lint::{lint_file, Severity}, types::*};
+use crate::planner::ReadPlanner;
+use crate::profiles::{series_files, Registry, Series};
+use crate::schedule::Schedule;
+use crate::simulator::{Simulator, SimulatorConfig};
+use crate::sinks::{self, SinkHandle};
+
+#[derive(Debug, Parser)]
+#[command(version, about = "Logs Huion SUN2000 inverter data to Redis, InfluxDB, MQTT or Prometheus")]
+pub struct Cli {
+    /// Config file (TOML, or JSON with a .json extension), defaults to ./config.toml
+    #[arg(short, long, env = "SOLAR_CONFIG")]
+    pub config: Option<String>,
+
+    /// Base key of the device the other commands talk to, defaults to the first one
+    #[arg(short, long, global = true)]
+    pub device: Option<String>,
+
+    /// Append every Modbus request and response to this file, overrides `inverter.capture`
+    #[arg(long, global = true)]
+    pub capture: Option<String>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Poll every device and store the values forever (default)
+    Run,
+    /// Read every signal once and print it to stdout
+    ReadOnce,
+    /// Print raw registers as hex, signed/unsigned and ASCII
+    DumpRegisters {
+        start: u16,
+        #[arg(default_value_t = 1)]
+        len: u16,
+        /// Read input registers (function 0x04) instead of holding registers
+        #[arg(long)]
+        input: bool,
+    },
+    /// Print the device id and nameplate values
+    Info,
+    /// Write a control parameter, the value is in engineering units (e.g. 50.5 for 50.5 %)
+    Write {
+        signal: String,
+        #[arg(allow_negative_numbers = true)]
+        value: f64,
+    },
+    /// Control the battery, checked against its rated powers
+    Battery {
+        #[command(subcommand)]
+        command: BatteryCommand,
+    },
+    /// Print recent alarm and state events from the history in Redis, which has
+    /// to be enabled in the config, or with --watch from the inverter
+    Events {
+        /// Number of events to show
+        #[arg(short = 'n', long, default_value_t = 20)]
+        count: usize,
+        /// Poll the inverter and print events as they happen instead of
+        /// reading the history stored in Redis
+        #[arg(short, long)]
+        watch: bool,
+    },
+    /// Validate the config file and print the effective settings
+    CheckConfig,
+    /// Check definition files (and the series files next to them) for overlapping registers,
+    /// duplicate names, wrong lengths, unknown dtypes and zero gains
+    LintDefinitions {
+        #[arg(default_value = parser::DEFAULT_DEFINITIONS)]
+        files: Vec<String>,
+    },
+    /// Pretend to be the inverter of the device, serving Modbus TCP from its definitions
+    Simulate {
+        /// Address to listen on
+        #[arg(long, default_value = "127.0.0.1:5020")]
+        listen: String,
+        #[command(flatten)]
+        config: SimulatorConfig,
+    },
+}
+
+/// Picks the device for the commands that talk to a single one
+pub fn device(config: &Config, base_key: Option<&str>) -> Device {
+    config.device(base_key).unwrap_or_else(|| {
+        eprintln!("No device with base_key {}", base_key.unwrap_or_default());
+        process::exit(1);
+    })
+}
+
+/// Sets up the data logger without any sinks, the connection is made on the first request
+fn logger(config: &Config, device: &Device, connection: Connection) -> DataLogger {
+    let profiles = Registry::load(&device.definitions).expect("Definitions were validated on startup");
+    let planner = ReadPlanner::new(device.inverter.max_block_size, device.inverter.max_gap);
+    let schedule = Schedule::new(profiles.base(), device.poll_interval()).expect("Intervals were validated on startup");
+    DataLogger::new(connection, profiles, planner, schedule, config.event_history, device.pack_detect_interval())
+}
+
+fn capture(inverter: &InverterConfig) -> Option<Capture> {
+    inverter.capture.as_ref().map(|path| {
+        Capture::open(path).unwrap_or_else(|e| {
+            eprintln!("Unable to open the capture {}: {}", path, e);
+            process::exit(1);
+        })
+    })
+}
+
+fn connection(device: &Device, capture: Option<Capture>) -> Connection {
+    let transport = device.transport().expect("Transport was validated on startup");
+    Connection::new(transport, Slave(device.inverter.unit_id), device.timeout())
+        .with_pacer(Pacer::new(device.request_delay()))
+        .with_capture(capture)
+}
+
+async fn connect(config: &Config, device: &Device, blocking: bool) -> DataLogger {
+    let mut connection = connection(device, capture(&device.inverter));
+    if blocking {
+        connection.connect_blocking().await;
+    } else if !connection.ensure_connected().await {
+        fail(ModbusError::NotConnected);
+    }
+    logger(config, device, connection)
+}
+
+fn fail(err: ModbusError) -> ! {
+    eprintln!("Reading from the inverter failed: {}", err);
+    process::exit(1);
+}
+
+fn print_signals(category: &str, signals: &[PVSignal]) {
+    for s in signals {
+        match (s.scaled(), s.data.label()) {
+            (Some(v), _) => println!("{:<8} {:<36} {:>12} {:<4} (raw {}, gain {})", category, s.name, v, s.unit, s.data, s.gain),
+            (None, Some(label)) => println!("{:<8} {:<36} {:>12} {}", category, s.name, s.data, label),
+            (None, None) => println!("{:<8} {:<36} {:>12} {:<4}", category, s.name, s.data, s.unit),
+        }
+    }
+}
+
+/// Delay before trying again after initializing or reading a device failed
+const RETRY: Duration = Duration::from_secs(5);
+/// How long queued data may take to reach the sinks when shutting down
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A device polled by `run` on its own schedule
+struct Poller {
+    device: Device,
+    datalogger: DataLogger,
+    initialized: bool,
+    export_limit: Option<ExportLimitConfig>,
+    limiter: Option<ExportLimiter>,
+    next_cycle: Instant,
+}
+
+impl Poller {
+    async fn init(&mut self) -> bool {
+        let key = &self.device.base_key;
+        if !self.initialized {
+            info!("{}: Detecting the model, batteries and meter", key);
+            match self.datalogger.init().await {
+                Ok(()) => self.initialized = true,
+                Err(e) => {
+                    warn!("{}: Initialization failed: {}, retrying", key, e);
+                    return false;
+                }
+            }
+        }
+        if let (Some(export_limit), None) = (&self.export_limit, &self.limiter) {
+            match ExportLimiter::new(export_limit, &mut self.datalogger).await {
+                Ok(l) => self.limiter = Some(l),
+                Err(e) => warn!("{}: Unable to start the export limiter: {}, retrying", key, e),
+            }
+        }
+        true
+    }
+
+    /// Reads the due signals and queues them for the sinks. Dropping the
+    /// future half way is fine, values are only taken over once every
+    /// request of a category succeeded and the connection recovers from
+    /// requests cut off on the wire.
+    async fn cycle(&mut self) {
+        if !self.init().await {
+            self.next_cycle = Instant::now() + RETRY;
+            return;
+        }
+        let key = self.device.base_key.clone();
+        debug!("{}: Starting new gathering cycle", key);
+        let readstart = Instant::now();
+        let wait = match self.datalogger.read_data().await {
+            Ok(()) => {
+                let readdur = readstart.elapsed();
+                debug!("{}: Reading Registers took: {}s", key, readdur.as_secs());
+                // the sinks work through their queues on their own tasks
+                self.datalogger.send_data(key.clone());
+                // only static values left, keep publishing the status
+                self.datalogger.until_next_read().unwrap_or(self.device.poll_interval())
+            },
+            Err(e) => {
+                warn!("{}: Skipping cycle: {} ({} consecutive failures)", key, e, self.datalogger.connection_stats().consecutive_failures);
+                RETRY
+            }
+        };
+        self.datalogger.send_status(key);
+        self.next_cycle = Instant::now() + wait;
+    }
+}
+
+/// Polls devices that share an address one after the other, so the
+/// inverters behind one SDongle never see overlapping requests. Stops as
+/// soon as `stop` is set, also in the middle of a cycle, and lifts the
+/// export limit.
+async fn poll(mut pollers: Vec<Poller>, mut stop: watch::Receiver<bool>) {
+    _poll(&mut pollers, &mut stop).await;
+    for p in pollers.iter_mut() {
+        if let Some(limiter) = p.limiter.as_mut() {
+            if let Err(e) = limiter.release(&mut p.datalogger).await {
+                error!("{}: Unable to lift the active power limit: {}", p.device.base_key, e);
+            }
+        }
+    }
+}
+
+async fn _poll(pollers: &mut [Poller], stop: &mut watch::Receiver<bool>) {
+    loop {
+        for p in pollers.iter_mut().filter(|p| p.next_cycle <= Instant::now()) {
+            tokio::select! {
+                _ = p.cycle() => {}
+                _ = stop.changed() => return,
+            }
+        }
+        let deadline = pollers.iter().map(|p| p.next_cycle).min().unwrap();
+        // the export limiter keeps running between the cycles
+        let wait = async {
+            match pollers.iter_mut().find(|p| p.limiter.is_some()) {
+                Some(Poller { limiter: Some(l), datalogger, .. }) => l.run_until(datalogger, deadline).await,
+                _ => time::sleep_until(deadline.into()).await,
+            }
+        };
+        tokio::select! {
+            _ = wait => {}
+            _ = stop.changed() => return,
+        }
+    }
+}
+
+/// Resolves on the first SIGINT or SIGTERM
+async fn shutdown_signal() {
+    let mut term = signal(SignalKind::terminate()).expect("Unable to listen for SIGTERM");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => info!("Got SIGINT"),
+        _ = term.recv() => info!("Got SIGTERM"),
+    }
+}
+
+pub async fn run(config: &Config) {
+    let mut sinks = Vec::new();
+    let mut sink_tasks = Vec::new();
+    let enabled = sinks::from_config(config).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
+    for sink in enabled {
+        let (handle, task) = SinkHandle::spawn(sink);
+        sinks.push(handle);
+        sink_tasks.push(task);
+    }
+
+    // one task per address, devices on different sites don't wait for each other
+    let capture = capture(&config.inverter);
+    let mut sites: Vec<(String, Connection, Vec<Poller>)> = Vec::new();
+    let limit_device = config.export_limit_device().map(|d| d.base_key);
+    for device in config.devices() {
+        if !sites.iter().any(|(address, ..)| *address == device.inverter.address) {
+            sites.push((device.inverter.address.clone(), connection(&device, capture.clone()), Vec::new()));
+        }
+        let (_, site, pollers) = sites.iter_mut().find(|(address, ..)| *address == device.inverter.address).unwrap();
+        // the devices at one address share the pacer and, on a serial bus, the port
+        let mut datalogger = logger(config, &device, site.to_slave(Slave(device.inverter.unit_id)));
+        for sink in sinks.iter() {
+            datalogger.add_sink(sink.clone());
+        }
+        pollers.push(Poller {
+            datalogger,
+            initialized: false,
+            export_limit: (Some(&device.base_key) == limit_device.as_ref()).then(|| config.export_limit.clone()),
+            limiter: None,
+            next_cycle: Instant::now(),
+            device,
+        });
+    }
+    // the pollers hold the remaining handles, the sinks stop once they are gone
+    drop(sinks);
+
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let mut site_tasks = Vec::new();
+    for (address, _, pollers) in sites {
+        info!("Polling {} device(s) at {}", pollers.len(), address);
+        site_tasks.push(tokio::spawn(poll(pollers, stop_rx.clone())));
+    }
+
+    shutdown_signal().await;
+    info!("Shutting down");
+    let _ = stop_tx.send(true);
+    for task in site_tasks {
+        if let Err(e) = task.await {
+            error!("Polling task failed: {}", e);
+        }
+    }
+    // give the sinks a chance to write what was read last
+    let drain = async {
+        for task in sink_tasks {
+            let _ = task.await;
+        }
+    };
+    if time::timeout(DRAIN_TIMEOUT, drain).await.is_err() {
+        warn!("Sinks didn't finish within {}s, exiting anyway", DRAIN_TIMEOUT.as_secs());
+    }
+}
+
+pub async fn read_once(config: &Config, device: &Device) {
+    let mut datalogger = connect(config, device, false).await;
+    datalogger.init().await.unwrap_or_else(|e| fail(e));
+    datalogger.read_data().await.unwrap_or_else(|e| fail(e));
+
+    print_signals("general", datalogger._get_general_data());
+    print_signals("storage", datalogger._get_storage_data());
+    print_signals("pgs", datalogger._get_pgs_data());
+    for pv in datalogger._get_pvs() {
+        print_signals("pv", &[pv.voltage.clone(), pv.current.clone()]);
+    }
+}
+
+pub async fn dump_registers(config: &Config, device: &Device, start: u16, len: u16, input: bool) {
+    let mut datalogger = connect(config, device, false).await;
+    let data = datalogger.read_registers(start, len, input).await.unwrap_or_else(|e| fail(e));
+
+    println!("{:<7} {:<6} {:>6} {:>7} ascii", "addr", "hex", "u16", "i16");
+    for (i, w) in data.iter().enumerate() {
+        let ascii: String = [(w >> 8) as u8, (w & 0xFF) as u8]
+            .iter()
+            .map(|&c| if c.is_ascii_graphic() || c == b' ' { c as char } else { '.' })
+            .collect();
+        println!("{:<7} {:#06x} {:>6} {:>7} {}", start as u32 + i as u32, w, w, *w as i16, ascii);
+    }
+}
+
+pub async fn info(config: &Config, device: &Device) {
+    let mut datalogger = connect(config, device, false).await;
+    let did = datalogger.read_device_id().await.unwrap_or_else(|e| fail(e));
+    println!("Device ID: {}", did);
+    match Series::detect(&did) {
+        Some(series) => println!("Series: {}", series),
+        None => println!("Series: unknown, the base definitions are used"),
+    }
+    let signals = datalogger.read_static_data().await.unwrap_or_else(|e| fail(e));
+    print_signals("static", &signals);
+}
+
+pub async fn write(config: &Config, device: &Device, signal: &str, value: f64) {
+    let mut datalogger = connect(config, device, false).await;
+    match datalogger.write_signal(signal, value).await {
+        Ok(s) => print_signals("written", &[s]),
+        Err(e) => {
+            eprintln!("Writing {} failed: {}", signal, e);
+            process::exit(1);
+        }
+    }
+}
+
+pub async fn battery(config: &Config, device: &Device, command: &BatteryCommand) {
+    let mut datalogger = connect(config, device, false).await;
+    match battery::execute(&mut datalogger, command).await {
+        Ok(written) => print_signals("written", &written),
+        Err(e) => {
+            print_signals("written", &e.written);
+            eprintln!("Battery command failed: {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+pub async fn events(config: &Config, device: &Device, count: usize, watch: bool) {
+    if watch {
+        return watch_events(config, device).await;
+    }
+    if !config.redis.enabled {
+        eprintln!("The event history is stored in Redis, enable it or use --watch");
+        process::exit(1);
+    }
+    let sink = sinks::redis::RedisSink::new(&config.redis);
+    match sink.read_events(&device.base_key, count).await {
+        Ok(events) => events.iter().for_each(|e| println!("{}", e)),
+        Err(e) => {
+            eprintln!("Reading events from Redis failed: {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+/// Polls the inverter without any sinks and prints every new event
+async fn watch_events(config: &Config, device: &Device) {
+    let mut datalogger = connect(config, device, true).await;
+    while let Err(e) = datalogger.init().await {
+        warn!("Initialization failed: {}, retrying", e);
+        time::sleep(RETRY).await;
+    }
+    let mut seen = 0;
+    loop {
+        let wait = match datalogger.read_data().await {
+            Ok(()) => {
+                datalogger.send_data(device.base_key.clone());
+                datalogger.until_next_read().unwrap_or(device.poll_interval())
+            }
+            Err(e) => {
+                warn!("Skipping cycle: {}", e);
+                RETRY
+            }
+        };
+        let history = datalogger.event_history();
+        let new = (datalogger.events_recorded() - seen).min(history.len());
+        for e in history.iter().skip(history.len() - new) {
+            println!("{}", e);
+        }
+        seen = datalogger.events_recorded();
+        time::sleep(wait).await;
+    }
+}
+
+/// Loading already validated the config, so just show what will be used.
+/// Without a config file there is nothing to check.
+pub fn check_config(config: &Config, path: &str) {
+    if !Path::new(path).exists() {
+        eprintln!("Config file {} not found", path);
+        process::exit(1);
+    }
+    println!("{} is valid, effective settings:\n", path);
+    print!("{}", toml::to_string_pretty(config).unwrap());
+}
+
+/// Prints every finding, exiting with 1 if there are errors. Doesn't need
+/// a valid config, which broken definitions would prevent.
+pub fn lint_definitions(files: &[String]) -> ! {
+    let mut failed = false;
+    let files = files.iter().flat_map(|f| std::iter::once(f.clone()).chain(series_files(f).into_iter().map(|(_, s)| s)));
+    for file in files {
+        match lint_file(&file) {
+            Ok(findings) => {
+                let errors = findings.iter().filter(|f| f.severity == Severity::Error).count();
+                println!("{}: {} error(s), {} warning(s)", file, errors, findings.len() - errors);
+                for f in findings {
+                    println!("  {}", f);
+                }
+                failed |= errors > 0;
+            }
+            Err(e) => {
+                println!("{}: can't be loaded: {}", file, e);
+                failed = true;
+            }
+        }
+    }
+    process::exit(if failed { 1 } else { 0 });
+}
+
+pub async fn simulate(device: &Device, listen: &str, config: SimulatorConfig) {
+    let definitions = parser::read_definitions(&device.definitions).expect("Definitions were validated on startup");
+    let simulator = Simulator::new(&definitions, device.inverter.unit_id, config).unwrap_or_else(|e| {
+        eprintln!("Invalid simulator settings: {}", e);
+        process::exit(1);
+    });
+    let listener = TcpListener::bind(listen).await.unwrap_or_else(|e| {
+        eprintln!("Unable to listen on {}: {}", listen, e);
+        process::exit(1);
+    });
+    info!("Simulating unit {} of {} on {}", device.inverter.unit_id, device.base_key, listen);
+    tokio::select! {
+        res = simulator.serve(listener) => {
+            if let Err(e) = res {
+                error!("Simulator stopped: {}", e);
+            }
+        }
+        _ = shutdown_signal() => info!("Shutting down"),
+    }
+}
@@ -0,0 +1,817 @@
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fmt;
+use std::fs;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::Path;
+use std::time::Duration;
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::connection::{SerialSettings, Transport, DEFAULT_TIMEOUT};
+use crate::parser::lint::{lint_file, Severity};
+use crate::parser::DEFAULT_DEFINITIONS;
+use crate::planner::{DEFAULT_MAX_GAP, MAX_BLOCK_SIZE};
+use crate::profiles::{series_files, Registry};
+use crate::schedule::Schedule;
+
+pub const DEFAULT_CONFIG: &str = "./config.toml";
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(String, std::io::Error),
+    Parse(String, String),
+    Invalid(Vec<String>),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, e) => write!(f, "unable to read config file {}: {}", path, e),
+            ConfigError::Parse(path, e) => write!(f, "invalid config file {}: {}", path, e),
+            ConfigError::Invalid(errors) => {
+                writeln!(f, "invalid configuration:")?;
+                for e in errors {
+                    writeln!(f, "  - {}", e)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Which values a sink writes: as read from the inverter, with the gain applied, or both
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ValueMode {
+    Raw,
+    Scaled,
+    Both,
+}
+
+/// Modbus TCP over the network, Modbus RTU over a serial port or the
+/// replay of a capture
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TransportKind {
+    Tcp,
+    Rtu,
+    Replay,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Parity {
+    None,
+    Even,
+    Odd,
+}
+
+impl From<Parity> for tokio_serial::Parity {
+    fn from(parity: Parity) -> Self {
+        match parity {
+            Parity::None => tokio_serial::Parity::None,
+            Parity::Even => tokio_serial::Parity::Even,
+            Parity::Odd => tokio_serial::Parity::Odd,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct InverterConfig {
+    pub transport: TransportKind,
+    /// `host:port` of the inverter or SDongle, the port defaults to 502.
+    /// The serial port (e.g. `/dev/ttyUSB0`) for `rtu`, the capture file for `replay`.
+    pub address: String,
+    pub unit_id: u8,
+    /// Serial line settings for `rtu`, 8 data bits and 1 stop bit
+    pub baud_rate: u32,
+    pub parity: Parity,
+    pub timeout_secs: u64,
+    pub max_block_size: u16,
+    pub max_gap: u16,
+    /// Minimum time between two requests to the same address
+    pub request_delay_ms: u64,
+    /// Seconds between looking for added or removed battery packs and checking the meter, 0 to only look at startup
+    pub pack_detect_interval_secs: u64,
+    /// File every Modbus request and response gets appended to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub capture: Option<String>,
+}
+
+impl Default for InverterConfig {
+    fn default() -> Self {
+        InverterConfig {
+            transport: TransportKind::Tcp,
+            address: String::new(),
+            unit_id: 1,
+            baud_rate: 9600,
+            parity: Parity::None,
+            timeout_secs: DEFAULT_TIMEOUT.as_secs(),
+            max_block_size: MAX_BLOCK_SIZE,
+            max_gap: DEFAULT_MAX_GAP,
+            request_delay_ms: 50,
+            pack_detect_interval_secs: 3600,
+            capture: None,
+        }
+    }
+}
+
+/// An additional inverter, anything left out is taken from the top level
+/// and `[inverter]`. Cascaded inverters share the address of the SDongle
+/// (or the serial port of the RS485 bus) and differ in their unit id.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DeviceConfig {
+    pub base_key: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub transport: Option<TransportKind>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub address: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unit_id: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub definitions: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub poll_interval_secs: Option<u64>,
+}
+
+/// A device to poll with the defaults filled in
+#[derive(Debug, Clone, PartialEq)]
+pub struct Device {
+    pub base_key: String,
+    pub inverter: InverterConfig,
+    pub definitions: String,
+    pub poll_interval_secs: u64,
+}
+
+impl Device {
+    /// Resolves the address, adding the default Modbus port if missing
+    pub fn addr(&self) -> Result<SocketAddr, String> {
+        let addr = if self.inverter.address.contains(':') {
+            self.inverter.address.clone()
+        } else {
+            format!("{}:502", self.inverter.address)
+        };
+        addr.to_socket_addrs()
+            .ok()
+            .and_then(|mut a| a.next())
+            .ok_or(format!("not a valid host:port, got {}", self.inverter.address))
+    }
+
+    pub fn transport(&self) -> Result<Transport, String> {
+        match self.inverter.transport {
+            TransportKind::Tcp if self.inverter.address.is_empty() => Err("empty, expected host:port".to_string()),
+            TransportKind::Tcp => self.addr().map(Transport::Tcp),
+            TransportKind::Rtu if self.inverter.address.is_empty() => Err("empty, expected a serial port".to_string()),
+            TransportKind::Rtu if self.inverter.baud_rate == 0 => Err("not usable with a baud_rate of 0".to_string()),
+            TransportKind::Rtu => Ok(Transport::Rtu(SerialSettings {
+                path: self.inverter.address.clone(),
+                baud_rate: self.inverter.baud_rate,
+                parity: self.inverter.parity.into(),
+            })),
+            TransportKind::Replay if self.inverter.address.is_empty() => Err("empty, expected a capture file".to_string()),
+            TransportKind::Replay => Ok(Transport::Replay(self.inverter.address.clone())),
+        }
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.inverter.timeout_secs)
+    }
+
+    pub fn pack_detect_interval(&self) -> Duration {
+        Duration::from_secs(self.inverter.pack_detect_interval_secs)
+    }
+
+    pub fn request_delay(&self) -> Duration {
+        Duration::from_millis(self.inverter.request_delay_ms)
+    }
+
+    pub fn poll_interval(&self) -> Duration {
+        Duration::from_secs(self.poll_interval_secs)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RedisConfig {
+    pub enabled: bool,
+    pub url: String,
+    /// `both` writes the scaled values to an extra `<key>:scaled` series
+    pub values: ValueMode,
+    /// Approximate number of entries kept in the `<base_key>:events` stream
+    pub events_max_len: usize,
+}
+
+impl Default for RedisConfig {
+    fn default() -> Self {
+        RedisConfig {
+            enabled: true,
+            url: String::new(),
+            values: ValueMode::Raw,
+            events_max_len: 10000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct InfluxConfig {
+    pub enabled: bool,
+    /// Base URL of the InfluxDB server, `/api/v2/write` gets appended
+    pub url: String,
+    pub org: String,
+    pub bucket: String,
+    pub token: String,
+    pub timeout_secs: u64,
+    /// `both` adds a `<field>_raw` integer field next to each scaled one
+    pub values: ValueMode,
+}
+
+impl Default for InfluxConfig {
+    fn default() -> Self {
+        InfluxConfig {
+            enabled: false,
+            url: "http://localhost:8086".to_string(),
+            org: String::new(),
+            bucket: "solar".to_string(),
+            token: String::new(),
+            timeout_secs: 10,
+            values: ValueMode::Scaled,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MqttConfig {
+    pub enabled: bool,
+    pub host: String,
+    pub port: u16,
+    pub client_id: String,
+    pub username: String,
+    pub password: String,
+    /// Values end up in `<topic_prefix>/<base_key>/<category>/<signal>`
+    pub topic_prefix: String,
+    /// Publish Home Assistant MQTT discovery payloads
+    pub discovery: bool,
+    pub discovery_prefix: String,
+    /// `both` publishes the raw value to an extra `<topic>/raw`
+    pub values: ValueMode,
+}
+
+impl Default for MqttConfig {
+    fn default() -> Self {
+        MqttConfig {
+            enabled: false,
+            host: "localhost".to_string(),
+            port: 1883,
+            client_id: "solar_getter".to_string(),
+            username: String::new(),
+            password: String::new(),
+            topic_prefix: "solar".to_string(),
+            discovery: true,
+            discovery_prefix: "homeassistant".to_string(),
+            values: ValueMode::Scaled,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PrometheusConfig {
+    pub enabled: bool,
+    /// Address the `/metrics` endpoint listens on
+    pub listen: String,
+    /// `both` adds a `<metric>_raw` gauge next to each scaled one
+    pub values: ValueMode,
+}
+
+impl Default for PrometheusConfig {
+    fn default() -> Self {
+        PrometheusConfig {
+            enabled: false,
+            listen: "0.0.0.0:9898".to_string(),
+            values: ValueMode::Scaled,
+        }
+    }
+}
+
+/// Zero-export controller, adjusts the inverter's active power limit between cycles
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ExportLimitConfig {
+    pub enabled: bool,
+    /// Base key of the device the meter is connected to, may be left out
+    /// without `[[devices]]`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device: Option<String>,
+    /// Highest power in watts that may flow into the grid, 0 for zero export
+    pub max_export_w: f64,
+    /// Seconds between two meter reads
+    pub interval_secs: u64,
+    /// How far the limit may be raised per step, lowering is never delayed
+    pub max_step_up_w: f64,
+    /// Changes smaller than this are not written
+    pub deadband_w: f64,
+    /// Limit used while the meter can't be read
+    pub fallback_limit_w: f64,
+}
+
+impl Default for ExportLimitConfig {
+    fn default() -> Self {
+        ExportLimitConfig {
+            enabled: false,
+            device: None,
+            max_export_w: 0.0,
+            interval_secs: 5,
+            max_step_up_w: 500.0,
+            deadband_w: 50.0,
+            fallback_limit_w: 0.0,
+        }
+    }
+}
+
+impl ExportLimitConfig {
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval_secs)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Namespace for everything written by the sinks
+    pub base_key: String,
+    pub inverter: InverterConfig,
+    pub redis: RedisConfig,
+    pub influx: InfluxConfig,
+    pub mqtt: MqttConfig,
+    pub prometheus: PrometheusConfig,
+    pub export_limit: ExportLimitConfig,
+    pub poll_interval_secs: u64,
+    pub definitions: String,
+    pub log_level: String,
+    /// Alarm and state events kept in memory
+    pub event_history: usize,
+    /// Inverters to poll instead of the single one described by the top level
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub devices: Vec<DeviceConfig>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            base_key: "solar".to_string(),
+            inverter: InverterConfig::default(),
+            redis: RedisConfig::default(),
+            influx: InfluxConfig::default(),
+            mqtt: MqttConfig::default(),
+            prometheus: PrometheusConfig::default(),
+            export_limit: ExportLimitConfig::default(),
+            poll_interval_secs: 90,
+            definitions: DEFAULT_DEFINITIONS.to_string(),
+            log_level: "info".to_string(),
+            event_history: 500,
+            devices: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    /// Loads the config file (TOML, or JSON for `.json` files), then applies
+    /// `INV_IP`/`RD_IP` overrides and validates the result. Without a `path`
+    /// [`DEFAULT_CONFIG`] is used if it exists, the defaults and environment
+    /// otherwise. A given `path` has to exist.
+    pub fn load(path: Option<&str>) -> Result<Config, ConfigError> {
+        let mut config = match path {
+            None if !Path::new(DEFAULT_CONFIG).exists() => {
+                eprintln!("Config file {} not found, using defaults and the environment", DEFAULT_CONFIG);
+                Config::default()
+            }
+            _ => {
+                let path = path.unwrap_or(DEFAULT_CONFIG);
+                let content = fs::read_to_string(path).map_err(|e| ConfigError::Read(path.to_string(), e))?;
+                Config::parse(path, &content)?
+            }
+        };
+        config.apply_env();
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn parse(path: &str, content: &str) -> Result<Config, ConfigError> {
+        if path.ends_with(".json") {
+            serde_json::from_str(content).map_err(|e| ConfigError::Parse(path.to_string(), e.to_string()))
+        } else {
+            toml::from_str(content).map_err(|e| ConfigError::Parse(path.to_string(), e.to_string()))
+        }
+    }
+
+    fn apply_env(&mut self) {
+        if let Ok(v) = env::var("INV_IP") {
+            self.inverter.address = v;
+        }
+        if let Ok(v) = env::var("RD_IP") {
+            self.redis.url = v;
+        }
+    }
+
+    /// Collects every problem instead of stopping at the first one
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut errors = Vec::new();
+        if self.inverter.timeout_secs == 0 {
+            errors.push("inverter.timeout_secs must be greater than 0".to_string());
+        }
+        if self.inverter.max_block_size == 0 || self.inverter.max_block_size > MAX_BLOCK_SIZE {
+            errors.push(format!("inverter.max_block_size must be between 1 and {}, got {}", MAX_BLOCK_SIZE, self.inverter.max_block_size));
+        }
+        let devices = self.devices();
+        let mut seen = HashSet::new();
+        let mut linted = HashSet::new();
+        for d in devices.iter() {
+            // the single device keeps the names of the top level settings
+            let name = |field: &str| match self.devices.is_empty() {
+                true if field == "address" || field == "unit_id" => format!("inverter.{}", field),
+                true => field.to_string(),
+                false => format!("device {}: {}", d.base_key, field),
+            };
+            if let Err(e) = d.transport() {
+                errors.push(format!("{} is {}", name("address"), e));
+            }
+            if d.inverter.unit_id > 247 {
+                errors.push(format!("{} must be between 0 and 247, got {}", name("unit_id"), d.inverter.unit_id));
+            }
+            if d.base_key.is_empty() || d.base_key.contains(char::is_whitespace) {
+                errors.push(format!("{} must be non-empty without whitespace, got {:?}", name("base_key"), d.base_key));
+            }
+            if !seen.insert(d.base_key.as_str()) {
+                errors.push(format!("base_key {} is used by more than one device", d.base_key));
+            }
+            if d.poll_interval_secs == 0 {
+                errors.push(format!("{} must be greater than 0", name("poll_interval_secs")));
+            }
+            if linted.insert(d.definitions.as_str()) {
+                let files = std::iter::once(d.definitions.clone()).chain(series_files(&d.definitions).into_iter().map(|(_, f)| f));
+                for file in files {
+                    // a file that can't be read is reported below
+                    for finding in lint_file(&file).unwrap_or_default().into_iter().filter(|f| f.severity == Severity::Error) {
+                        errors.push(format!("{} {}: {}", name("definitions"), file, finding.message));
+                    }
+                }
+            }
+            match Registry::load(&d.definitions) {
+                Ok(profiles) => {
+                    for (series, defs) in profiles.all() {
+                        if let Err(e) = Schedule::new(defs, d.poll_interval()) {
+                            errors.push(format!("{} {} has invalid intervals for {}: {}", name("definitions"), d.definitions, series, e));
+                        }
+                    }
+                }
+                Err(e) => errors.push(format!("{} {} can't be loaded: {}", name("definitions"), d.definitions, e)),
+            }
+        }
+        let mut slaves = HashSet::new();
+        // devices at one address share the connection of the first one
+        let mut links = HashMap::new();
+        for d in devices.iter() {
+            if !slaves.insert((d.inverter.address.as_str(), d.inverter.unit_id)) {
+                errors.push(format!("unit {} at {} is configured more than once", d.inverter.unit_id, d.inverter.address));
+            }
+            let i = &d.inverter;
+            let link = (i.transport, i.baud_rate, i.parity, i.timeout_secs);
+            if *links.entry(i.address.as_str()).or_insert(link) != link {
+                errors.push(format!("device {}: transport, baud_rate, parity and timeout_secs must match the other devices at {}", d.base_key, i.address));
+            }
+        }
+        if self.redis.url.is_empty() {
+            if self.redis.enabled {
+                errors.push("redis.url must be set".to_string());
+            }
+        } else if let Err(e) = redis::Client::open(self.redis.url.as_str()) {
+            errors.push(format!("redis.url {} is invalid: {}", self.redis.url, e));
+        }
+        if self.influx.enabled {
+            // built without TLS support, put a proxy in front for https
+            if !self.influx.url.starts_with("http://") {
+                errors.push(format!("influx.url must start with http://, got {}", self.influx.url));
+            }
+            if self.influx.bucket.is_empty() {
+                errors.push("influx.bucket must be set".to_string());
+            }
+        }
+        if self.mqtt.enabled {
+            if self.mqtt.host.is_empty() {
+                errors.push("mqtt.host must be set".to_string());
+            }
+            if self.mqtt.client_id.is_empty() {
+                errors.push("mqtt.client_id must be set".to_string());
+            }
+            if self.mqtt.topic_prefix.contains(['+', '#']) || self.mqtt.discovery_prefix.contains(['+', '#']) {
+                errors.push("mqtt prefixes must not contain wildcards".to_string());
+            }
+        }
+        if self.prometheus.enabled && self.prometheus.listen.parse::<SocketAddr>().is_err() {
+            errors.push(format!("prometheus.listen is not a valid ip:port, got {}", self.prometheus.listen));
+        }
+        if self.export_limit.enabled {
+            let limit = &self.export_limit;
+            match self.export_limit_device() {
+                Some(d) if limit.interval_secs == 0 || limit.interval_secs >= d.poll_interval_secs => {
+                    errors.push(format!("export_limit.interval_secs must be between 1 and poll_interval_secs, got {}", limit.interval_secs));
+                }
+                Some(_) => {}
+                None => errors.push(format!("export_limit.device must be the base_key of one of the devices, got {:?}", limit.device)),
+            }
+            if limit.max_export_w < 0.0 || limit.fallback_limit_w < 0.0 || limit.deadband_w < 0.0 {
+                errors.push("export_limit powers must not be negative".to_string());
+            }
+            if limit.max_step_up_w <= 0.0 {
+                errors.push("export_limit.max_step_up_w must be greater than 0".to_string());
+            }
+        }
+        if self.event_history == 0 {
+            errors.push("event_history must be greater than 0".to_string());
+        }
+        if self.log_level.parse::<log::LevelFilter>().is_err() {
+            errors.push(format!("log_level must be one of off, error, warn, info, debug, trace, got {}", self.log_level));
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Invalid(errors))
+        }
+    }
+
+    /// Every device to poll
+    pub fn devices(&self) -> Vec<Device> {
+        if self.devices.is_empty() {
+            return vec![Device {
+                base_key: self.base_key.clone(),
+                inverter: self.inverter.clone(),
+                definitions: self.definitions.clone(),
+                poll_interval_secs: self.poll_interval_secs,
+            }];
+        }
+        self.devices.iter().map(|d| {
+            let mut inverter = self.inverter.clone();
+            inverter.transport = d.transport.unwrap_or(inverter.transport);
+            if let Some(address) = &d.address {
+                inverter.address = address.clone();
+            }
+            inverter.unit_id = d.unit_id.unwrap_or(inverter.unit_id);
+            Device {
+                base_key: d.base_key.clone(),
+                inverter,
+                definitions: d.definitions.clone().unwrap_or_else(|| self.definitions.clone()),
+                poll_interval_secs: d.poll_interval_secs.unwrap_or(self.poll_interval_secs),
+            }
+        }).collect()
+    }
+
+    /// The device with the meter the export limiter reads, `None` if it's
+    /// disabled or the device isn't known
+    pub fn export_limit_device(&self) -> Option<Device> {
+        if !self.export_limit.enabled {
+            return None;
+        }
+        match &self.export_limit.device {
+            Some(key) => self.device(Some(key)),
+            None if self.devices.is_empty() => self.device(None),
+            None => None,
+        }
+    }
+
+    /// The device called `base_key`, or the first one
+    pub fn device(&self, base_key: Option<&str>) -> Option<Device> {
+        let mut devices = self.devices().into_iter();
+        match base_key {
+            Some(key) => devices.find(|d| d.base_key == key),
+            None => devices.next(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The defaults with the settings that have none
+    fn config() -> Config {
+        let mut config = Config::default();
+        config.inverter.address = "10.0.0.5".to_string();
+        config.redis.url = "redis://localhost/".to_string();
+        config
+    }
+
+    #[test]
+    fn defaults_need_addresses() {
+        assert!(config().validate().is_ok());
+        match Config::default().validate() {
+            Err(ConfigError::Invalid(errors)) => assert_eq!(errors, vec!["inverter.address is empty, expected host:port", "redis.url must be set"]),
+            other => panic!("expected validation errors, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn given_file_has_to_exist() {
+        assert!(matches!(Config::load(Some("./missing.toml")), Err(ConfigError::Read(_, _))));
+    }
+
+    #[test]
+    fn parses_partial_toml() {
+        let config = Config::parse("test.toml", r#"
+            poll_interval_secs = 30
+
+            [inverter]
+            address = "10.0.0.5"
+            unit_id = 2
+        "#).unwrap();
+        assert_eq!(config.poll_interval_secs, 30);
+        assert_eq!(config.inverter.unit_id, 2);
+        assert_eq!(config.inverter.timeout_secs, 10);
+        assert_eq!(config.redis, RedisConfig::default());
+        assert_eq!(config.devices()[0].addr().unwrap(), "10.0.0.5:502".parse().unwrap());
+    }
+
+    #[test]
+    fn parses_json() {
+        let config = Config::parse("test.json", r#"{"base_key": "roof", "redis": {"enabled": false, "values": "both"}}"#).unwrap();
+        assert_eq!(config.base_key, "roof");
+        assert!(!config.redis.enabled);
+        assert_eq!(config.redis.values, ValueMode::Both);
+    }
+
+    #[test]
+    fn rejects_unknown_keys() {
+        assert!(matches!(Config::parse("test.toml", "poll_interval = 5"), Err(ConfigError::Parse(_, _))));
+    }
+
+    #[test]
+    fn reports_all_errors() {
+        let mut config = config();
+        config.inverter.address = "not an address".to_string();
+        config.poll_interval_secs = 0;
+        config.log_level = "loud".to_string();
+        match config.validate() {
+            Err(ConfigError::Invalid(errors)) => assert_eq!(errors.len(), 3),
+            other => panic!("expected validation errors, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn devices_inherit_the_top_level() {
+        let config = Config::parse("test.toml", r#"
+            poll_interval_secs = 60
+
+            [inverter]
+            address = "10.0.0.5"
+            timeout_secs = 3
+
+            [redis]
+            url = "redis://localhost/"
+
+            [[devices]]
+            base_key = "master"
+
+            [[devices]]
+            base_key = "cascaded"
+            unit_id = 2
+            poll_interval_secs = 120
+
+            [[devices]]
+            base_key = "barn"
+            address = "10.0.1.7:6607"
+        "#).unwrap();
+        assert!(config.validate().is_ok());
+        let devices = config.devices();
+        assert_eq!(devices.iter().map(|d| (d.base_key.as_str(), d.inverter.unit_id, d.poll_interval_secs)).collect::<Vec<_>>(),
+            vec![("master", 1, 60), ("cascaded", 2, 120), ("barn", 1, 60)]);
+        assert_eq!(devices[1].addr().unwrap(), "10.0.0.5:502".parse().unwrap());
+        assert_eq!(devices[2].addr().unwrap(), "10.0.1.7:6607".parse().unwrap());
+        assert_eq!(devices[2].timeout(), Duration::from_secs(3));
+        assert_eq!(config.device(Some("barn")), Some(devices[2].clone()));
+        assert_eq!(config.device(None), Some(devices[0].clone()));
+    }
+
+    #[test]
+    fn parses_serial_devices() {
+        let config = Config::parse("test.toml", r#"
+            [inverter]
+            transport = "rtu"
+            address = "/dev/ttyUSB0"
+            parity = "even"
+
+            [redis]
+            url = "redis://localhost/"
+
+            [[devices]]
+            base_key = "bus1"
+
+            [[devices]]
+            base_key = "bus2"
+            unit_id = 2
+
+            [[devices]]
+            base_key = "network"
+            transport = "tcp"
+            address = "10.0.0.5"
+
+            [[devices]]
+            base_key = "recorded"
+            transport = "replay"
+            address = "capture.jsonl"
+        "#).unwrap();
+        assert!(config.validate().is_ok());
+        let devices = config.devices();
+        assert_eq!(devices[1].transport(), Ok(Transport::Rtu(SerialSettings {
+            path: "/dev/ttyUSB0".to_string(),
+            baud_rate: 9600,
+            parity: tokio_serial::Parity::Even,
+        })));
+        assert_eq!(devices[2].transport(), Ok(Transport::Tcp("10.0.0.5:502".parse().unwrap())));
+        assert_eq!(devices[3].transport(), Ok(Transport::Replay("capture.jsonl".to_string())));
+    }
+
+    #[test]
+    fn rejects_duplicate_devices() {
+        let config = Config {
+            devices: vec![
+                DeviceConfig { base_key: "a".to_string(), ..Default::default() },
+                DeviceConfig { base_key: "a".to_string(), unit_id: Some(2), ..Default::default() },
+                DeviceConfig { base_key: "b".to_string(), unit_id: Some(2), ..Default::default() },
+            ],
+            ..config()
+        };
+        match config.validate() {
+            Err(ConfigError::Invalid(errors)) => assert_eq!(errors.len(), 2, "{:?}", errors),
+            other => panic!("expected validation errors, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn rejects_different_links_to_one_address() {
+        let mut config = Config {
+            devices: vec![
+                DeviceConfig { base_key: "a".to_string(), ..Default::default() },
+                DeviceConfig { base_key: "b".to_string(), unit_id: Some(2), transport: Some(TransportKind::Replay), ..Default::default() },
+            ],
+            ..config()
+        };
+        config.inverter.transport = TransportKind::Rtu;
+        config.inverter.address = "/dev/ttyUSB0".to_string();
+        match config.validate() {
+            Err(ConfigError::Invalid(errors)) => assert_eq!(errors, vec!["device b: transport, baud_rate, parity and timeout_secs must match the other devices at /dev/ttyUSB0"]),
+            other => panic!("expected validation errors, got {:?}", other),
+        }
+        config.devices[1].transport = None;
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn runs_the_export_limiter_on_the_named_device() {
+        let mut config = Config {
+            devices: vec![
+                DeviceConfig { base_key: "master".to_string(), ..Default::default() },
+                DeviceConfig { base_key: "cascaded".to_string(), unit_id: Some(2), ..Default::default() },
+            ],
+            export_limit: ExportLimitConfig { enabled: true, ..Default::default() },
+            ..config()
+        };
+        match config.validate() {
+            Err(ConfigError::Invalid(errors)) => assert_eq!(errors, vec!["export_limit.device must be the base_key of one of the devices, got None"]),
+            other => panic!("expected validation errors, got {:?}", other),
+        }
+        config.export_limit.device = Some("cascaded".to_string());
+        assert!(config.validate().is_ok());
+        assert_eq!(config.export_limit_device().unwrap().base_key, "cascaded");
+        // a single inverter needs no name
+        config.devices.clear();
+        config.export_limit.device = None;
+        assert_eq!(config.export_limit_device().unwrap().base_key, "solar");
+    }
+
+    #[test]
+    fn rejects_broken_definitions() {
+        let dir = std::env::temp_dir().join(format!("solar_getter_definitions_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        // extends is relative to the file
+        std::fs::copy(DEFAULT_DEFINITIONS, dir.join("definitions.json")).unwrap();
+        std::fs::write(dir.join("broken.json"), r#"{
+            "extends": "definitions.json",
+            "const": [{"dtype": "I32", "addr": 32107, "len": 2, "gain": 1, "name": "grid_power", "unit": "W", "category": 0}]
+        }"#).unwrap();
+        let config = Config { definitions: dir.join("broken.json").to_string_lossy().into_owned(), ..config() };
+        let res = config.validate();
+        std::fs::remove_dir_all(&dir).unwrap();
+        match res {
+            Err(ConfigError::Invalid(errors)) => {
+                assert_eq!(errors.len(), 1, "{:?}", errors);
+                assert!(errors[0].ends_with("acc_energy_yield (32106-32107) overlaps grid_power (32107-32108)"), "{}", errors[0]);
+            }
+            other => panic!("expected validation errors, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,568 @@
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use rand::prelude::*;
+use tokio::sync::Mutex;
+use tokio::time::{self, timeout};
+use tokio_modbus::{client::Context, prelude::*};
+use tokio_serial::{DataBits, Parity, SerialPortBuilderExt, StopBits};
+
+use crate::capture::{Capture, Exchange, Replay, READ_HOLDING_REGISTERS, READ_INPUT_REGISTERS};
+
+/// Default timeout for a single Modbus request
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+pub enum ModbusError {
+    /// Transport problem, the connection gets dropped and re-established
+    Io(io::Error),
+    /// The inverter answered with an exception, the connection is still fine
+    Exception(String),
+    /// No connection is available right now (e.g. waiting for the next retry)
+    NotConnected,
+}
+
+impl fmt::Display for ModbusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ModbusError::Io(e) => write!(f, "I/O error: {}", e),
+            ModbusError::Exception(e) => write!(f, "exception: {}", e),
+            ModbusError::NotConnected => write!(f, "not connected"),
+        }
+    }
+}
+
+impl From<io::Error> for ModbusError {
+    fn from(err: io::Error) -> Self {
+        // tokio-modbus doesn't export its exception type, but wraps it in an
+        // `Other` error whose message starts with "Modbus function"
+        let is_exception = err.kind() == io::ErrorKind::Other
+            && err.get_ref().map(|e| e.to_string().starts_with("Modbus function")).unwrap_or(false);
+        if is_exception {
+            ModbusError::Exception(err.to_string())
+        } else {
+            ModbusError::Io(err)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    Connected,
+    Disconnected,
+}
+
+impl fmt::Display for ConnectionState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectionState::Connected => write!(f, "connected"),
+            ConnectionState::Disconnected => write!(f, "disconnected"),
+        }
+    }
+}
+
+/// Counters describing the health of the inverter connection
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionStats {
+    pub connects: u64,
+    pub connect_failures: u64,
+    pub requests: u64,
+    pub io_errors: u64,
+    pub exceptions: u64,
+    pub skipped_cycles: u64,
+    pub consecutive_failures: u32,
+    pub last_error: Option<String>,
+    /// Unix millis of the last successful request
+    pub last_success: i64,
+}
+
+/// Exponential backoff with jitter
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    base: Duration,
+    max: Duration,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new(base: Duration, max: Duration) -> Backoff {
+        Backoff { base, max, attempt: 0 }
+    }
+
+    /// Returns the delay before the next attempt, somewhere between half and
+    /// the full exponential delay so multiple loggers don't retry in lockstep
+    pub fn next_delay(&mut self) -> Duration {
+        let exp = self.base.saturating_mul(2u32.saturating_pow(self.attempt)).min(self.max);
+        self.attempt = self.attempt.saturating_add(1);
+        let half = exp / 2;
+        half + half.mul_f64(thread_rng().gen::<f64>())
+    }
+
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
+
+/// Turns a timed out request into an I/O error, the connection gets dropped
+/// because the late response would be taken for the next one
+fn flatten<T>(res: Result<io::Result<T>, time::error::Elapsed>) -> io::Result<T> {
+    res.unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "request timed out")))
+}
+
+/// Keeps a minimum delay between requests. Connections to the same
+/// inverter or SDongle share one so the device is never flooded.
+#[derive(Debug, Clone)]
+pub struct Pacer {
+    delay: Duration,
+    last: Arc<Mutex<Option<Instant>>>,
+}
+
+impl Pacer {
+    pub fn new(delay: Duration) -> Pacer {
+        Pacer { delay, last: Arc::new(Mutex::new(None)) }
+    }
+
+    /// Sleeps until `delay` has passed since the previous request
+    pub async fn wait(&self) {
+        let mut last = self.last.lock().await;
+        if let Some(at) = *last {
+            time::sleep_until((at + self.delay).into()).await;
+        }
+        *last = Some(Instant::now());
+    }
+}
+
+/// Serial port settings for Modbus RTU, always 8 data bits and 1 stop bit
+#[derive(Debug, Clone, PartialEq)]
+pub struct SerialSettings {
+    pub path: String,
+    pub baud_rate: u32,
+    pub parity: Parity,
+}
+
+/// How the inverter is reached
+#[derive(Debug, Clone, PartialEq)]
+pub enum Transport {
+    /// Modbus TCP, directly or through the SDongle
+    Tcp(SocketAddr),
+    /// Modbus RTU over RS485
+    Rtu(SerialSettings),
+    /// Answers from a capture file instead of an inverter
+    Replay(String),
+}
+
+impl fmt::Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Transport::Tcp(addr) => write!(f, "{}", addr),
+            Transport::Rtu(serial) => write!(f, "{}", serial.path),
+            Transport::Replay(path) => write!(f, "{}", path),
+        }
+    }
+}
+
+impl Transport {
+    async fn connect(&self, slave: Slave) -> io::Result<Context> {
+        match self {
+            Transport::Tcp(addr) => tcp::connect_slave(*addr, slave).await,
+            Transport::Rtu(serial) => {
+                let port = tokio_serial::new(&serial.path, serial.baud_rate)
+                    .data_bits(DataBits::Eight)
+                    .parity(serial.parity)
+                    .stop_bits(StopBits::One)
+                    .open_native_async()?;
+                Ok(rtu::attach_slave(port, slave))
+            }
+            Transport::Replay(path) => {
+                let mut replay = Replay::load(path).await?;
+                replay.set_slave(slave);
+                Ok(Context::from(Box::new(replay) as Box<dyn Client>))
+            }
+        }
+    }
+}
+
+/// The Modbus context behind a connection
+#[derive(Default)]
+struct Link {
+    ctx: Option<Context>,
+    /// Set while a request is on the wire. If it is still set on the next
+    /// request, the previous one got cancelled and its response may still
+    /// arrive, so the connection can't be trusted anymore.
+    in_flight: bool,
+}
+
+/// Supervises the Modbus connection to the inverter, reconnecting after
+/// transport errors instead of bringing the whole logger down.
+pub struct Connection {
+    transport: Transport,
+    slave: Slave,
+    timeout: Duration,
+    /// Shared by the connections to the slaves on one serial port, which
+    /// can only be opened once
+    link: Arc<Mutex<Link>>,
+    backoff: Backoff,
+    retry_at: Option<Instant>,
+    pacer: Pacer,
+    capture: Option<Capture>,
+    stats: ConnectionStats,
+}
+
+impl fmt::Debug for Connection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Connection")
+            .field("transport", &self.transport)
+            .field("slave", &self.slave)
+            .field("state", &self.state())
+            .field("stats", &self.stats)
+            .finish()
+    }
+}
+
+impl Connection {
+    pub fn new(transport: Transport, slave: Slave, timeout: Duration) -> Connection {
+        Connection {
+            transport,
+            slave,
+            timeout,
+            link: Arc::new(Mutex::new(Link::default())),
+            backoff: Backoff::new(Duration::from_secs(1), Duration::from_secs(300)),
+            retry_at: None,
+            pacer: Pacer::new(Duration::ZERO),
+            capture: None,
+            stats: ConnectionStats::default(),
+        }
+    }
+
+    /// Spaces the requests with `pacer`, which may be shared with other connections
+    pub fn with_pacer(mut self, pacer: Pacer) -> Connection {
+        self.pacer = pacer;
+        self
+    }
+
+    /// Records every request and its response to `capture`
+    pub fn with_capture(mut self, capture: Option<Capture>) -> Connection {
+        self.capture = capture;
+        self
+    }
+
+    /// Connection to another slave at the same address. Slaves on a serial
+    /// bus share the port, over TCP each one gets its own connection.
+    pub fn to_slave(&self, slave: Slave) -> Connection {
+        let mut connection = Connection::new(self.transport.clone(), slave, self.timeout).with_pacer(self.pacer.clone()).with_capture(self.capture.clone());
+        if let Transport::Rtu(_) = self.transport {
+            connection.link = self.link.clone();
+        }
+        connection
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        // locked while a request is on the wire
+        match self.link.try_lock().map(|link| link.ctx.is_some()) {
+            Ok(false) => ConnectionState::Disconnected,
+            _ => ConnectionState::Connected,
+        }
+    }
+
+    pub fn stats(&self) -> &ConnectionStats {
+        &self.stats
+    }
+
+    /// Records that a gathering cycle was dropped because of connection problems
+    pub fn skip_cycle(&mut self) {
+        self.stats.skipped_cycles += 1;
+    }
+
+    /// Waits until a connection could be established
+    pub async fn connect_blocking(&mut self) {
+        while !self.ensure_connected().await {
+            if let Some(at) = self.retry_at {
+                time::sleep_until(at.into()).await;
+            }
+        }
+    }
+
+    /// Tries to (re)connect unless the backoff delay hasn't passed yet
+    pub async fn ensure_connected(&mut self) -> bool {
+        let link = self.link.clone();
+        let mut link = link.lock().await;
+        if link.in_flight {
+            warn!("Dropping the connection to {} after a cancelled request", self.transport);
+            link.ctx = None;
+            link.in_flight = false;
+        }
+        if link.ctx.is_some() {
+            return true;
+        }
+        if let Some(at) = self.retry_at {
+            if Instant::now() < at {
+                debug!("Next connection attempt in {}s", (at - Instant::now()).as_secs());
+                return false;
+            }
+        }
+
+        info!("Connecting to {} ({:?})", self.transport, self.slave);
+        let res = match timeout(self.timeout, self.transport.connect(self.slave)).await {
+            Ok(res) => res,
+            Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "connection timed out")),
+        };
+        match res {
+            Ok(ctx) => {
+                link.ctx = Some(ctx);
+                self.stats.connects += 1;
+                self.backoff.reset();
+                self.retry_at = None;
+                true
+            }
+            Err(e) => {
+                let delay = self.backoff.next_delay();
+                warn!("Connection to {} failed: {}, retrying in {}s", self.transport, e, delay.as_secs());
+                self.stats.connect_failures += 1;
+                self.stats.last_error = Some(e.to_string());
+                self.retry_at = Some(Instant::now() + delay);
+                false
+            }
+        }
+    }
+
+    pub async fn read_holding_registers(&mut self, address: u16, count: u16) -> Result<Vec<u16>, ModbusError> {
+        self.read(READ_HOLDING_REGISTERS, address, count).await
+    }
+
+    pub async fn read_input_registers(&mut self, address: u16, count: u16) -> Result<Vec<u16>, ModbusError> {
+        self.read(READ_INPUT_REGISTERS, address, count).await
+    }
+
+    async fn read(&mut self, function: u8, address: u16, count: u16) -> Result<Vec<u16>, ModbusError> {
+        if !self.ensure_connected().await {
+            return Err(ModbusError::NotConnected);
+        }
+        self.pacer.wait().await;
+        let link = self.link.clone();
+        let mut link = link.lock().await;
+        let Link { ctx, in_flight } = &mut *link;
+        // dropped by a request to another slave on the bus
+        let ctx = ctx.as_mut().ok_or(ModbusError::NotConnected)?;
+        ctx.set_slave(self.slave);
+        self.stats.requests += 1;
+        *in_flight = true;
+        let request = async {
+            match function {
+                READ_INPUT_REGISTERS => ctx.read_input_registers(address, count).await,
+                _ => ctx.read_holding_registers(address, count).await,
+            }
+        };
+        let res = flatten(timeout(self.timeout, request).await).map_err(ModbusError::from);
+        *in_flight = false;
+        if let Some(capture) = &self.capture {
+            capture.record(&Exchange::read(self.slave.0, function, address, count, &res));
+        }
+        match res {
+            Ok(data) => {
+                self.stats.consecutive_failures = 0;
+                self.stats.last_success = chrono::Utc::now().timestamp_millis();
+                Ok(data)
+            }
+            Err(e) => Err(self.handle_error(&mut link, e, address)),
+        }
+    }
+
+    /// Writes `data` starting at `address`, single registers use function 0x06
+    pub async fn write_registers(&mut self, address: u16, data: &[u16]) -> Result<(), ModbusError> {
+        if !self.ensure_connected().await {
+            return Err(ModbusError::NotConnected);
+        }
+        self.pacer.wait().await;
+        let link = self.link.clone();
+        let mut link = link.lock().await;
+        let Link { ctx, in_flight } = &mut *link;
+        // dropped by a request to another slave on the bus
+        let ctx = ctx.as_mut().ok_or(ModbusError::NotConnected)?;
+        ctx.set_slave(self.slave);
+        self.stats.requests += 1;
+        *in_flight = true;
+        let res = match data {
+            [value] => flatten(timeout(self.timeout, ctx.write_single_register(address, *value)).await),
+            _ => flatten(timeout(self.timeout, ctx.write_multiple_registers(address, data)).await),
+        }.map_err(ModbusError::from);
+        *in_flight = false;
+        if let Some(capture) = &self.capture {
+            capture.record(&Exchange::write(self.slave.0, address, data, &res));
+        }
+        match res {
+            Ok(()) => {
+                self.stats.consecutive_failures = 0;
+                self.stats.last_success = chrono::Utc::now().timestamp_millis();
+                Ok(())
+            }
+            Err(e) => Err(self.handle_error(&mut link, e, address)),
+        }
+    }
+
+    fn handle_error(&mut self, link: &mut Link, err: ModbusError, address: u16) -> ModbusError {
+        self.stats.consecutive_failures += 1;
+        self.stats.last_error = Some(err.to_string());
+        match &err {
+            ModbusError::Exception(_) => {
+                self.stats.exceptions += 1;
+                warn!("Register {} returned {}", address, err);
+            }
+            // a slave that doesn't answer says nothing about the port, which
+            // the other slaves on the bus still need
+            ModbusError::Io(e) if e.kind() == io::ErrorKind::TimedOut && matches!(self.transport, Transport::Rtu(_)) => {
+                self.stats.io_errors += 1;
+                warn!("Unit {} didn't answer the request for register {}", self.slave.0, address);
+            }
+            _ => {
+                self.stats.io_errors += 1;
+                warn!("Request for register {} failed: {}, dropping connection", address, err);
+                link.ctx = None;
+                self.retry_at = Some(Instant::now() + self.backoff.next_delay());
+            }
+        }
+        err
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_grows_and_caps() {
+        let mut b = Backoff::new(Duration::from_secs(1), Duration::from_secs(8));
+        let delays: Vec<Duration> = (0..6).map(|_| b.next_delay()).collect();
+        for (i, d) in delays.iter().enumerate() {
+            let exp = Duration::from_secs(1 << i.min(3));
+            assert!(*d >= exp / 2 && *d <= exp, "attempt {}: {:?}", i, d);
+        }
+        b.reset();
+        assert!(b.next_delay() <= Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn pacer_spaces_requests() {
+        let pacer = Pacer::new(Duration::from_millis(30));
+        let shared = pacer.clone();
+        let start = Instant::now();
+        pacer.wait().await;
+        shared.wait().await;
+        pacer.wait().await;
+        assert!(start.elapsed() >= Duration::from_millis(60));
+    }
+
+    #[test]
+    fn classifies_errors() {
+        let timeout = io::Error::new(io::ErrorKind::TimedOut, "timeout");
+        assert!(matches!(ModbusError::from(timeout), ModbusError::Io(_)));
+        let exc = io::Error::other("Modbus function 3: Illegal data address");
+        assert!(matches!(ModbusError::from(exc), ModbusError::Exception(_)));
+    }
+
+    #[tokio::test]
+    async fn unreachable_inverter_backs_off() {
+        // nothing listens on port 1 of localhost
+        let mut con = Connection::new(Transport::Tcp("127.0.0.1:1".parse().unwrap()), Slave(1), Duration::from_millis(200));
+        assert!(matches!(con.read_holding_registers(30000, 1).await, Err(ModbusError::NotConnected)));
+        assert_eq!(con.state(), ConnectionState::Disconnected);
+        // second attempt is held back by the backoff
+        assert!(!con.ensure_connected().await);
+        assert_eq!(con.stats().connect_failures, 1);
+    }
+
+    #[tokio::test]
+    async fn reconnects_after_a_cancelled_request() {
+        // accepts connections but never answers
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut streams = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                streams.push(stream);
+            }
+        });
+        let mut con = Connection::new(Transport::Tcp(address), Slave(1), Duration::from_secs(5));
+        assert!(con.ensure_connected().await);
+        // the request is dropped while waiting for the reply
+        assert!(time::timeout(Duration::from_millis(100), con.read_holding_registers(30000, 1)).await.is_err());
+        // a late reply to it must not be taken for the next one
+        assert!(con.ensure_connected().await);
+        assert_eq!(con.stats().connects, 2);
+    }
+
+    /// CRC of a Modbus RTU frame
+    fn crc16(data: &[u8]) -> u16 {
+        let mut crc = 0xFFFF;
+        for b in data {
+            crc ^= *b as u16;
+            for _ in 0..8 {
+                crc = if crc & 1 == 1 { (crc >> 1) ^ 0xA001 } else { crc >> 1 };
+            }
+        }
+        crc
+    }
+
+    /// Answers reads on the other end of a pseudo-terminal, register `n`
+    /// of slave `s` holds `s * 100 + n`. Slave `absent` never answers.
+    async fn rtu_slave(mut port: tokio_serial::SerialStream, absent: u8) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let mut request = [0u8; 8];
+        while port.read_exact(&mut request).await.is_ok() {
+            assert_eq!(request[1], 3, "only reads are simulated");
+            assert_eq!(crc16(&request[..6]).to_le_bytes(), request[6..]);
+            if request[0] == absent {
+                continue;
+            }
+            let address = u16::from_be_bytes([request[2], request[3]]);
+            let count = u16::from_be_bytes([request[4], request[5]]);
+            let mut reply = vec![request[0], 3, (count * 2) as u8];
+            for n in address..address + count {
+                reply.extend_from_slice(&(request[0] as u16 * 100 + n).to_be_bytes());
+            }
+            reply.extend_from_slice(&crc16(&reply).to_le_bytes());
+            port.write_all(&reply).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_slaves_on_a_serial_bus() {
+        use tokio_serial::SerialPort;
+        let (master, slave) = tokio_serial::SerialStream::pair().unwrap();
+        let path = slave.name().unwrap();
+        // the connection opens the port on its own
+        drop(slave);
+        let serial = SerialSettings { path, baud_rate: 9600, parity: Parity::Even };
+        let mut first = Connection::new(Transport::Rtu(serial), Slave(1), Duration::from_secs(2));
+        let mut second = first.to_slave(Slave(2));
+        assert!(first.ensure_connected().await);
+        tokio::spawn(rtu_slave(master, 0));
+        assert_eq!(first.read_holding_registers(10, 2).await.unwrap(), vec![110, 111]);
+        assert_eq!(second.read_holding_registers(3, 1).await.unwrap(), vec![203]);
+        assert_eq!(first.read_holding_registers(0, 1).await.unwrap(), vec![100]);
+        // the port was only opened once
+        assert_eq!((first.stats().connects, second.stats().connects), (1, 0));
+        assert_eq!(second.state(), ConnectionState::Connected);
+    }
+
+    #[tokio::test]
+    async fn keeps_the_serial_port_for_a_missing_slave() {
+        use tokio_serial::SerialPort;
+        let (master, slave) = tokio_serial::SerialStream::pair().unwrap();
+        let path = slave.name().unwrap();
+        drop(slave);
+        let serial = SerialSettings { path, baud_rate: 9600, parity: Parity::None };
+        let mut present = Connection::new(Transport::Rtu(serial), Slave(1), Duration::from_millis(300));
+        let mut missing = present.to_slave(Slave(3));
+        assert!(present.ensure_connected().await);
+        tokio::spawn(rtu_slave(master, 3));
+        assert!(matches!(missing.read_holding_registers(0, 1).await, Err(ModbusError::Io(_))));
+        // the other slave goes on without waiting for a reconnect
+        assert_eq!(present.read_holding_registers(5, 1).await.unwrap(), vec![105]);
+        assert_eq!(present.stats().connects, 1);
+        assert_eq!(missing.stats().io_errors, 1);
+        assert_eq!(missing.state(), ConnectionState::Connected);
+    }
+}
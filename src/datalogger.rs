@@ -1,233 +1,460 @@
-use std::time::Instant;
-
-use redis::ToRedisArgs;
-use tokio_modbus::{client::sync, prelude::SyncReader};
-use rand::prelude::*;
-use crate::parser::{types::*, gen_constdata, gen_pvdata, gen_storagedata};
-
-extern crate redis;
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use crate::parser::{types::*, gen_constdata, gen_pvdata, gen_signal, gen_staticdata, gen_storagedata, pack_address, BATTERY_UNITS, PACKS_PER_UNIT, UNIT_SN_ADDRESSES};
+use crate::registers::{decode_string, decode_value, encode_value};
+use crate::planner::ReadPlanner;
+use crate::profiles::{Profile, Registry, Series, METER_STATUS_ADDRESS};
+use crate::connection::{ConnectionStats, ModbusError};
+use crate::events::{Event, EventLog};
+use crate::schedule::Schedule;
+use crate::sinks::{Category, Sample, SampleBatch, SinkHandle, Status};
+use crate::source::RegisterSource;
 
 #[derive(Debug)]
 pub struct DataLogger {
-    ctx: sync::Context,
-    redis_client: redis::Client,
+    source: Box<dyn RegisterSource>,
+    sinks: Vec<SinkHandle>,
+    /// Definitions of every series, `definitions` are picked from them
+    profiles: Registry,
+    /// Detected by `init`
+    profile: Option<Profile>,
+    definitions: Root,
     pvs: Vec<PVString>,
     general_data: Vec<PVSignal>,
     pgs_data: Vec<PVSignal>,
     storage_data: Vec<PVSignal>,
+    planner: ReadPlanner,
+    schedule: Schedule,
+    read_durations: BTreeMap<Category, Duration>,
+    /// Unix millis of the start of the current cycle
+    cycle_started: i64,
+    /// Unix millis of the last cycle that read every due signal
+    last_cycle: i64,
+    events: EventLog,
+    /// Installed battery packs, see `pack_address`
+    packs: Vec<u8>,
+    /// How often to look for added or removed packs and a meter that came
+    /// or went, zero for only at startup
+    pack_detect_interval: Duration,
+    packs_detected_at: Option<Instant>,
 }
 
-impl ToRedisArgs for PVSignalDataType {
-    fn write_redis_args<W>(&self, out: &mut W)
-    where
-        W: ?Sized + redis::RedisWrite,
-        Self: Sized, {
+#[derive(Debug)]
+pub enum WriteError {
+    UnknownSignal(String),
+    /// The name is used by more than one definition
+    Ambiguous(String),
+    NotWritable(String),
+    /// Outside of the range from the definitions or of the register type
+    OutOfRange(String, f64),
+    Modbus(ModbusError),
+    /// The inverter didn't keep the value, (written, read back)
+    Mismatch(PVSignalDataType, PVSignalDataType),
+    /// Rejected by a check that needs values from the device
+    Invalid(String),
+}
+
+impl fmt::Display for WriteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            PVSignalDataType::U16(x) => x.write_redis_args(out),
-            PVSignalDataType::I16(x) => x.write_redis_args(out),
-            PVSignalDataType::U32(x) => x.write_redis_args(out),
-            PVSignalDataType::I32(x) => x.write_redis_args(out),
-            PVSignalDataType::STR(x) => x.write_redis_args(out),
-            PVSignalDataType::UNK(x) => x.write_redis_args(out),
+            WriteError::UnknownSignal(name) => write!(f, "no signal named {}", name),
+            WriteError::Ambiguous(name) => write!(f, "{} is defined more than once", name),
+            WriteError::NotWritable(name) => write!(f, "{} is not writable", name),
+            WriteError::OutOfRange(name, value) => write!(f, "{} is out of range for {}", value, name),
+            WriteError::Modbus(e) => write!(f, "{}", e),
+            WriteError::Mismatch(written, read) => write!(f, "wrote {} but read back {}", written, read),
+            WriteError::Invalid(reason) => write!(f, "{}", reason),
         }
     }
+}
 
-    fn to_redis_args(&self) -> Vec<Vec<u8>> {
-        let mut out = Vec::new();
-        self.write_redis_args(&mut out);
-        out
-    }
-
-    fn describe_numeric_behavior(&self) -> redis::NumericBehavior {
-        redis::NumericBehavior::NonNumeric
+impl From<ModbusError> for WriteError {
+    fn from(err: ModbusError) -> Self {
+        WriteError::Modbus(err)
     }
+}
 
-    fn is_single_arg(&self) -> bool {
-        true
+/// The only definition called `name`
+fn find<'a>(defs: &'a Root, name: &str) -> Result<&'a Const, WriteError> {
+    let mut found = defs.const_field.iter().filter(|c| c.name == name);
+    match (found.next(), found.next()) {
+        (Some(def), None) => Ok(def),
+        (Some(_), Some(_)) => Err(WriteError::Ambiguous(name.to_string())),
+        (None, _) => Err(WriteError::UnknownSignal(name.to_string())),
     }
 }
 
-fn sort_data_redis(i: usize, base_key: &String, cat_key: String, base_data: &Vec<PVSignal>, con: &mut redis::Connection) {
-    match base_data[i].data {
-        PVSignalDataType::U16(v) => {
-            let _: () = redis::cmd("TS.ADD").arg(format!("{}:{}:{}", base_key, cat_key, base_data[i].name)).arg(base_data[i].time).arg(v.clone()).query(con).unwrap();
-        },
-        PVSignalDataType::I16(v) => {
-            let _: () = redis::cmd("TS.ADD").arg(format!("{}:{}:{}", base_key, cat_key, base_data[i].name)).arg(base_data[i].time).arg(v.clone()).query(con).unwrap();
-        },
-        PVSignalDataType::U32(v) => {
-            let _: () = redis::cmd("TS.ADD").arg(format!("{}:{}:{}", base_key, cat_key, base_data[i].name)).arg(base_data[i].time).arg(v.clone()).query(con).unwrap();
-        },
-        PVSignalDataType::I32(v) => {
-            let _: () = redis::cmd("TS.ADD").arg(format!("{}:{}:{}", base_key, cat_key, base_data[i].name)).arg(base_data[i].time).arg(v.clone()).query(con).unwrap();
-        },
-        PVSignalDataType::STR(_) => {
-            debug!("Skipping string: {}", base_data[i].name);
-        },
-        PVSignalDataType::UNK(_) => {
-            warn!("Got an unknown: {}", base_data[i].name);
-        }
+/// Checks a write of the engineering `value` against the definitions and
+/// returns the signal holding the raw value to write
+fn prepare_write(defs: &Root, name: &str, value: f64) -> Result<PVSignal, WriteError> {
+    let def = find(defs, name)?;
+    if !def.writable {
+        return Err(WriteError::NotWritable(name.to_string()));
+    }
+    let out_of_range = || WriteError::OutOfRange(name.to_string(), value);
+    if def.min.is_some_and(|min| value < min) || def.max.is_some_and(|max| value > max) {
+        return Err(out_of_range());
     }
+    let mut signal = gen_signal(def).ok_or_else(|| WriteError::NotWritable(name.to_string()))?;
+    // the inverse of the gain, e.g. 50.5 % with a gain of 10 is written as 505
+    // while 50.55 % can't be written at all. Only float errors like in
+    // 1.1 * 10 are rounded away.
+    let scaled = value * def.gain.max(1) as f64;
+    let raw = scaled.round();
+    if (scaled - raw).abs() > 1e-6 {
+        return Err(out_of_range());
+    }
+    signal.data = signal.data.with_raw(raw).ok_or_else(out_of_range)?;
+    Ok(signal)
 }
 
-fn sort_data_store(base_data: &mut Vec<PVSignal>, data: Vec<u16>) {
-    for i in 0..base_data.len() {
-        match base_data[i].data {
-            PVSignalDataType::U16(_) => {
-                base_data[i].data = PVSignalDataType::U16(data[i]);
-            },
-            PVSignalDataType::I16(_) => {
-                base_data[i].data = PVSignalDataType::I16(data[i] as i16);
-            },
-            PVSignalDataType::U32(_) => {
-                base_data[i].data = PVSignalDataType::U32((data[i] as u32) << 16 | data[i+1] as u32);
-            },
-            PVSignalDataType::I32(_) => {
-                base_data[i].data = PVSignalDataType::I32(((data[i] as u32) << 16 | data[i+1] as u32) as i32);
-            },
-            PVSignalDataType::STR(_) => {
-                let mut text = String::new();
-                for j in 0..base_data[i].length {
-                    text.push((data[i+j as usize] >> 8) as u8 as char);
-                    text.push((data[i+j as usize] & 0xFF) as u8 as char);
-                }
-                base_data[i].data = PVSignalDataType::STR(text);
-            },
-            PVSignalDataType::UNK(_) => base_data[i].data = PVSignalDataType::UNK(data[i]),
-        }
+/// Reads the signals of `base_data` that are due, returning how long the
+/// Modbus requests took or `None` if nothing was due
+async fn read_due(base_data: &mut [PVSignal], category: Category, schedule: &Schedule, source: &mut dyn RegisterSource, planner: &ReadPlanner, name: String) -> Result<Option<Duration>, ModbusError> {
+    let now = chrono::Utc::now().timestamp_millis();
+    let due: Vec<usize> = (0..base_data.len()).filter(|&i| schedule.is_due(category, &base_data[i], now)).collect();
+    if due.is_empty() {
+        return Ok(None);
     }
+    let mut signals: Vec<PVSignal> = due.iter().map(|&i| base_data[i].clone()).collect();
+    let rd = read_data(&mut signals, source, planner, name).await?;
+    for (i, s) in due.into_iter().zip(signals) {
+        base_data[i] = s;
+    }
+    Ok(Some(rd))
 }
 
-fn read_data(base_data: &mut Vec<PVSignal>, ctx: &mut sync::Context, name: String) {
-    let mut data: Vec<u16> = Vec::new();
+/// Reads and decodes `base_data`, returning how long the Modbus requests took
+async fn read_data(base_data: &mut [PVSignal], source: &mut dyn RegisterSource, planner: &ReadPlanner, name: String) -> Result<Duration, ModbusError> {
+    let plan = planner.plan(base_data);
+    let mut data: Vec<u16> = Vec::with_capacity(plan.map.total());
     let readstart = Instant::now();
-    for i in 0..base_data.len() {
-        debug!("Reading data for: {}", base_data[i].name);
-        let mut tmp: Vec<u16> = ctx.read_holding_registers(base_data[i].address, base_data[i].length).unwrap();
-        base_data[i].time = chrono::Utc::now().timestamp_millis();
+    for block in plan.blocks.iter() {
+        let blockstart = Instant::now();
+        let mut tmp: Vec<u16> = source.read_holding_registers(block.address, block.length).await?;
+        let time = chrono::Utc::now().timestamp_millis();
+        for &i in block.signals.iter() {
+            base_data[i].time = time;
+        }
+        debug!("{} Block {}+{} ({} signals): {}ms", name, block.address, block.length, block.signals.len(), blockstart.elapsed().as_millis());
         data.append(&mut tmp);
     }
     let rd = readstart.elapsed();
     let writestart = Instant::now();
-    sort_data_store(base_data,data);
+    plan.map.decode(base_data, &data);
     let wd = writestart.elapsed();
-    info!("{} Read: {}ms Write: {}ms", name, rd.as_millis(), wd.as_millis())
+    debug!("{} Read: {}ms ({} requests) Write: {}ms", name, rd.as_millis(), plan.blocks.len(), wd.as_millis());
+    Ok(rd)
 }
 
 impl DataLogger {
-    pub fn new(ctx: sync::Context, redis: redis::Client) -> DataLogger {
+    pub fn new(source: impl RegisterSource + 'static, profiles: Registry, planner: ReadPlanner, schedule: Schedule, event_history: usize, pack_detect_interval: Duration) -> DataLogger {
         DataLogger {
-            ctx,
-            redis_client: redis,
+            source: Box::new(source),
+            sinks: Vec::new(),
+            definitions: profiles.base().clone(),
+            profiles,
+            profile: None,
             pvs: Vec::new(),
             general_data: Vec::new(),
             pgs_data: Vec::new(),
             storage_data: Vec::new(),
+            planner,
+            schedule,
+            read_durations: BTreeMap::new(),
+            cycle_started: 0,
+            last_cycle: 0,
+            events: EventLog::new(event_history),
+            packs: Vec::new(),
+            pack_detect_interval,
+            packs_detected_at: None,
         }
     }
 
-    pub fn init(&mut self) {
-        let num_pvs = self._get_num_pvs() as u8;
+    /// Reads the model and looks for batteries and a meter to pick the
+    /// definitions to poll
+    pub async fn init(&mut self) -> Result<(), ModbusError> {
+        let model = match self.read_device_id().await {
+            Ok(model) => model,
+            Err(ModbusError::Exception(e)) => {
+                warn!("Unable to read the model: {}", e);
+                String::new()
+            }
+            Err(e) => return Err(e),
+        };
+        let series = Series::detect(&model);
+        if series.is_none() {
+            warn!("Unknown model {:?}, polling the base definitions", model);
+        }
+        let num_pvs = self._get_num_pvs().await? as u8;
         self.pvs = gen_pvdata(num_pvs);
-        self.general_data = gen_constdata(0);
-        self.pgs_data = gen_constdata(1);
-        self.storage_data = gen_storagedata();
-        self._test_redis();
+        self.packs = match series.is_none_or(|s| s.supports_battery()) {
+            true => self.detect_packs().await?,
+            false => Vec::new(),
+        };
+        info!("Found {} battery pack(s): {:?}", self.packs.len(), self.packs);
+        let meter = self.has_meter().await?;
+        let profile = Profile { series, battery: !self.packs.is_empty(), meter };
+        info!("Device {}: {}", model, profile);
+        self.apply_profile(profile);
+        Ok(())
     }
 
-    pub fn read_data(&mut self) {
-        info!("Reading data from inverter");
-        read_data(&mut self.general_data, &mut self.ctx, "General".to_string());
-        read_data(&mut self.storage_data, &mut self.ctx, "Storage".to_string());
-        read_data(&mut self.pgs_data, &mut self.ctx, "PGS".to_string());
-        self._read_pv_data();
+    fn apply_profile(&mut self, profile: Profile) {
+        self.definitions = self.profiles.definitions(&profile);
+        self.schedule = self.schedule.with_definitions(&self.definitions).expect("Intervals of every series were validated on startup");
+        self.general_data = gen_constdata(&self.definitions, 0);
+        self.pgs_data = gen_constdata(&self.definitions, 1);
+        self.storage_data = gen_storagedata(&self.definitions, &self.packs);
+        self.profile = Some(profile);
     }
 
-    pub fn send_data(&mut self, base_key: String) {
-        let mut con = self.redis_client.get_connection().unwrap();
-        // save data to redis using timeseries
-        // check if timeseries exists (returns Array with time series for each key or empty array)
-        let hastimeseries: Vec<String> = redis::cmd("TS.QUERYINDEX").arg(format!("base={}",base_key)).query(&mut con).unwrap();
-        debug!("{} + {} + {} + {} Values",self.general_data.len(), self.pgs_data.len(), self.storage_data.len(), self.pvs.len() * 2);
-        if hastimeseries.len() != (self.general_data.len() + self.storage_data.len() + self.pvs.len() * 2) {
-            // create timeseries for keys
-            debug!("Creating timeseries for keys");
-            for i in 0..self.general_data.len() {
-                let _: () = redis::cmd("TS.CREATE").arg(format!("{}:general:{}", base_key.to_owned(), self.general_data[i].name)).arg("LABELS").arg("base").arg(base_key.to_owned()).arg("type").arg("solar").arg("data").arg("general").query(&mut con).unwrap();
-            }
+    /// Whether a power meter is connected and working. Inverters without
+    /// one may also answer with an exception.
+    async fn has_meter(&mut self) -> Result<bool, ModbusError> {
+        match self.source.read_holding_registers(METER_STATUS_ADDRESS, 1).await {
+            Ok(status) => Ok(status == [1]),
+            Err(ModbusError::Exception(_)) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
 
-            for i in 0..self.storage_data.len() {
-                let _: () = redis::cmd("TS.CREATE").arg(format!("{}:storage:{}", base_key.to_owned(), self.storage_data[i].name)).arg("LABELS").arg("base").arg(base_key.to_owned()).arg("type").arg("solar").arg("data").arg("storage").query(&mut con).unwrap();
-            }
+    /// Whether there is a serial number at `address`. Registers of absent
+    /// units may also be answered with an exception.
+    async fn has_serial(&mut self, address: u16) -> Result<bool, ModbusError> {
+        match self.source.read_holding_registers(address, 10).await {
+            Ok(sn) => Ok(!decode_string(&sn).trim().is_empty()),
+            Err(ModbusError::Exception(_)) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
 
-            for i in 0..self.pgs_data.len() {
-                let _: () = redis::cmd("TS.CREATE").arg(format!("{}:pgs:{}", base_key.to_owned(), self.pgs_data[i].name)).arg("LABELS").arg("base").arg(base_key.to_owned()).arg("type").arg("solar").arg("data").arg("storage").query(&mut con).unwrap();
+    /// Finds the installed packs by their serial numbers, skipping units that aren't there
+    async fn detect_packs(&mut self) -> Result<Vec<u8>, ModbusError> {
+        let mut packs = Vec::new();
+        for unit in 0..BATTERY_UNITS {
+            if !self.has_serial(UNIT_SN_ADDRESSES[unit as usize]).await? {
+                continue;
             }
+            for ident in unit * PACKS_PER_UNIT..(unit + 1) * PACKS_PER_UNIT {
+                if self.has_serial(pack_address(ident)).await? {
+                    packs.push(ident);
+                }
+            }
+        }
+        self.packs_detected_at = Some(Instant::now());
+        Ok(packs)
+    }
 
-            for i in 0..self.pvs.len() {
-                let _: () = redis::cmd("TS.CREATE").arg(format!("{}:pv:{}", base_key.to_owned(), self.pvs[i].voltage.name)).arg("LABELS").arg("base").arg(base_key.to_owned()).arg("type").arg("solar").arg("data").arg("pv_volt").query(&mut con).unwrap();
-                let _: () = redis::cmd("TS.CREATE").arg(format!("{}:pv:{}", base_key.to_owned(), self.pvs[i].current.name)).arg("LABELS").arg("base").arg(base_key.to_owned()).arg("type").arg("solar").arg("data").arg("pv_curr").query(&mut con).unwrap();
+    /// Looks for added or removed packs and checks the meter once
+    /// `pack_detect_interval` has passed
+    async fn redetect_hardware(&mut self) {
+        let due = match self.packs_detected_at {
+            Some(at) => !self.pack_detect_interval.is_zero() && at.elapsed() >= self.pack_detect_interval,
+            None => false,
+        };
+        let Some(mut profile) = self.profile.filter(|_| due) else {
+            return;
+        };
+        let mut packs_changed = false;
+        match self.detect_packs().await {
+            Ok(packs) if packs != self.packs => {
+                info!("Battery packs changed from {:?} to {:?}", self.packs, packs);
+                profile.battery = !packs.is_empty();
+                self.packs = packs;
+                packs_changed = true;
+            }
+            Ok(_) => debug!("Battery packs unchanged: {:?}", self.packs),
+            Err(e) => warn!("Detecting battery packs failed: {}", e),
+        }
+        match self.has_meter().await {
+            Ok(meter) if meter != profile.meter => {
+                info!("Meter {}", if meter { "connected" } else { "disconnected" });
+                profile.meter = meter;
             }
+            Ok(_) => {}
+            Err(e) => warn!("Checking the meter failed: {}", e),
+        }
+        // the battery or meter came or went, so did their registers
+        if Some(profile) != self.profile {
+            self.apply_profile(profile);
+        } else if packs_changed {
+            self.storage_data = gen_storagedata(&self.definitions, &self.packs);
         }
+    }
+
+    /// Reads the model identification string (register 30000)
+    pub async fn read_device_id(&mut self) -> Result<String, ModbusError> {
+        let did: Vec<u16> = self.source.read_holding_registers(30000, 15).await?;
+        Ok(decode_string(&did))
+    }
 
-        // adding a lookup-table to database if it doesnt already exist
-        debug!("Updating/adding lookup table");
-        let mut creator = redis::cmd("HSET");
-        creator.arg(format!("{}:lookup",base_key));
-        let alldata: Vec<PVSignal> = [self.general_data.as_slice(), self.storage_data.as_slice(), self.pvs.iter().map(|x| [x.current.clone(), x.voltage.clone()]).flatten().collect::<Vec<PVSignal>>().as_slice()].concat();
-        for d in alldata.iter() {
-            creator.arg(&d.name).arg(&d.unit);
+    /// Reads `len` raw holding or input registers starting at `start`, split
+    /// into as many requests as needed
+    pub async fn read_registers(&mut self, start: u16, len: u16, input: bool) -> Result<Vec<u16>, ModbusError> {
+        let mut data = Vec::with_capacity(len as usize);
+        let mut addr = start as u32;
+        let end = start as u32 + len as u32;
+        if end > 0x10000 {
+            let e = format!("{} registers from {} run past the last address", len, start);
+            return Err(ModbusError::Io(std::io::Error::new(std::io::ErrorKind::InvalidInput, e)));
         }
-        let _: () = creator.query(&mut con).unwrap();
-        // adding a gain-scaling-table to database if it doesnt already exist
-        debug!("Updating/adding scaling table");
-        let mut creator = redis::cmd("HSET");
-        creator.arg(format!("{}:scaling",base_key));
-        let alldata: Vec<PVSignal> = [self.general_data.as_slice(), self.storage_data.as_slice(), self.pvs.iter().map(|x| [x.current.clone(), x.voltage.clone()]).flatten().collect::<Vec<PVSignal>>().as_slice()].concat();
-        for d in alldata.iter() {
-            creator.arg(&d.name).arg(&d.gain);
+        while addr < end {
+            let count = (end - addr).min(self.planner.max_block_size as u32) as u16;
+            let mut block = match input {
+                true => self.source.read_input_registers(addr as u16, count).await?,
+                false => self.source.read_holding_registers(addr as u16, count).await?,
+            };
+            data.append(&mut block);
+            addr += count as u32;
         }
-        let _: () = creator.query(&mut con).unwrap();
+        Ok(data)
+    }
 
-        info!("Saving data to redis");
+    /// Reads a single signal from the definitions, names used in several
+    /// categories can't be read
+    pub async fn read_signal(&mut self, name: &str) -> Result<PVSignal, WriteError> {
+        let def = find(&self.definitions, name)?;
+        let mut signals = vec![gen_signal(def).ok_or_else(|| WriteError::UnknownSignal(name.to_string()))?];
+        read_data(&mut signals, self.source.as_mut(), &self.planner, name.to_string()).await?;
+        Ok(signals.remove(0))
+    }
 
-        // save data to redis
-        for i in 0..self.general_data.len() {
-            sort_data_redis(i, &base_key, "general".to_string(), &self.general_data, &mut con);
+    /// Writes the engineering `value` to a writable signal from the definitions
+    /// and reads it back to make sure the inverter took it. Every attempt is
+    /// logged with the `audit` target.
+    pub async fn write_signal(&mut self, name: &str, value: f64) -> Result<PVSignal, WriteError> {
+        let res = self._write_signal(name, value).await;
+        match &res {
+            Ok(s) => info!(target: "audit", "Wrote {} = {} {} (raw {} to register {})", name, value, s.unit, s.data, s.address),
+            Err(e) => warn!(target: "audit", "Writing {} = {} failed: {}", name, value, e),
         }
+        res
+    }
+
+    async fn _write_signal(&mut self, name: &str, value: f64) -> Result<PVSignal, WriteError> {
+        let mut signal = prepare_write(&self.definitions, name, value)?;
+        let words = encode_value(&signal.data).ok_or_else(|| WriteError::NotWritable(name.to_string()))?;
+        self.source.write_registers(signal.address, &words).await?;
 
-        for i in 0..self.storage_data.len() {
-            sort_data_redis(i, &base_key, "storage".to_string(), &self.storage_data, &mut con);
+        let read = self.source.read_holding_registers(signal.address, signal.length).await?;
+        if read != words {
+            return Err(WriteError::Mismatch(signal.data.clone(), decode_value(&signal.data, &read)));
         }
+        signal.time = chrono::Utc::now().timestamp_millis();
+        Ok(signal)
+    }
+
+    /// Reads the nameplate values, which don't change while the inverter is running
+    pub async fn read_static_data(&mut self) -> Result<Vec<PVSignal>, ModbusError> {
+        let mut signals = gen_staticdata(&self.definitions);
+        read_data(&mut signals, self.source.as_mut(), &self.planner, "Static".to_string()).await?;
+        Ok(signals)
+    }
 
-        for i in 0..self.pgs_data.len() {
-            sort_data_redis(i, &base_key, "pgs".to_string(), &self.pgs_data, &mut con);
+    /// Reads the signals that are due, giving up on the cycle at the first failed request
+    pub async fn read_data(&mut self) -> Result<(), ModbusError> {
+        debug!("Reading data from inverter");
+        self.cycle_started = chrono::Utc::now().timestamp_millis();
+        let res = self._read_all().await;
+        match res {
+            Ok(()) => self.last_cycle = chrono::Utc::now().timestamp_millis(),
+            Err(_) => self.source.skip_cycle(),
         }
+        res
+    }
 
-        for i in 0..self.pvs.len() {
-            match self.pvs[i].voltage.data {
-                PVSignalDataType::I16(v) => {
-                    let _: () = redis::cmd("TS.ADD").arg(format!("{}:pv:{}", base_key, self.pvs[i].voltage.name)).arg(self.pvs[i].voltage.time).arg(v.clone()).query(&mut con).unwrap();
-                },
-                _ => {
-                    debug!("Skipping string: {}", self.pvs[i].voltage.name);
-                },
-            }
-            match self.pvs[i].current.data {
-                PVSignalDataType::I16(v) => {
-                    let _: () = redis::cmd("TS.ADD").arg(format!("{}:pv:{}", base_key, self.pvs[i].current.name)).arg(self.pvs[i].current.time).arg(v.clone()).query(&mut con).unwrap();
-                },
-                _ => {
-                    debug!("Skipping string: {}", self.pvs[i].current.name);
-                },
+    async fn _read_all(&mut self) -> Result<(), ModbusError> {
+        self.redetect_hardware().await;
+        let categories = [
+            (Category::General, &mut self.general_data, "General"),
+            (Category::Storage, &mut self.storage_data, "Storage"),
+            (Category::Pgs, &mut self.pgs_data, "PGS"),
+        ];
+        for (category, data, name) in categories {
+            if let Some(rd) = read_due(data, category, &self.schedule, self.source.as_mut(), &self.planner, name.to_string()).await? {
+                self.read_durations.insert(category, rd);
             }
-        
         }
+        self._read_pv_data().await
+    }
+
+    pub fn add_sink(&mut self, sink: SinkHandle) {
+        self.sinks.push(sink);
+    }
+
+    /// All values of the last cycle
+    pub fn samples(&self) -> Vec<Sample> {
+        let mut samples: Vec<Sample> = Vec::new();
+        samples.extend(self.general_data.iter().map(|s| Sample::from_signal(Category::General, s)));
+        samples.extend(self.storage_data.iter().map(|s| Sample::from_signal(Category::Storage, s)));
+        samples.extend(self.pgs_data.iter().map(|s| Sample::from_signal(Category::Pgs, s)));
+        for pv in self.pvs.iter() {
+            samples.push(Sample::from_signal(Category::Pv, &pv.voltage));
+            samples.push(Sample::from_signal(Category::Pv, &pv.current));
+        }
+        samples
+    }
+
+    /// Time until the next signal is due, `None` if everything is only read once
+    pub fn until_next_read(&self) -> Option<Duration> {
+        let general = self.general_data.iter().map(|s| (Category::General, s));
+        let storage = self.storage_data.iter().map(|s| (Category::Storage, s));
+        let pgs = self.pgs_data.iter().map(|s| (Category::Pgs, s));
+        let pv = self.pvs.iter().flat_map(|pv| [(Category::Pv, &pv.voltage), (Category::Pv, &pv.current)]);
+        let next = general.chain(storage).chain(pgs).chain(pv).filter_map(|(c, s)| self.schedule.next_read(c, s)).min()?;
+        Some(Duration::from_millis((next - chrono::Utc::now().timestamp_millis()).max(0) as u64))
+    }
+
+    /// Queues the values read in the last cycle for every sink
+    pub fn send_data(&mut self, base_key: String) {
+        let samples = self.samples().into_iter().filter(|s| s.time >= self.cycle_started).collect();
+        let batch = SampleBatch { base_key, samples };
+        let events = self.events.update(&batch.samples);
+        for sink in self.sinks.iter() {
+            sink.send(batch.clone());
+        }
+
+        if events.is_empty() {
+            return;
+        }
+        for e in events.iter() {
+            info!("Event: {}", e);
+        }
+        for sink in self.sinks.iter() {
+            sink.send_events(batch.base_key.clone(), events.clone());
+        }
+    }
+
+    /// Publishes the connection state and counters, also while the inverter is unreachable
+    pub fn send_status(&mut self, base_key: String) {
+        let status = Status {
+            base_key,
+            state: self.source.state(),
+            stats: self.source.stats().clone(),
+            read_durations: self.read_durations.clone(),
+            last_cycle: self.last_cycle,
+        };
+        for sink in self.sinks.iter() {
+            sink.send_status(status.clone());
+        }
+    }
 
-        // close the connection
-        let _: () = redis::cmd("QUIT").query(&mut con).unwrap();
+    /// Alarm and state events of the recent cycles, oldest first
+    pub fn event_history(&self) -> &VecDeque<Event> {
+        self.events.history()
     }
 
+    /// Number of events seen since the start, the history only keeps the latest
+    pub fn events_recorded(&self) -> usize {
+        self.events.recorded()
+    }
+
+    pub fn connection_stats(&self) -> &ConnectionStats {
+        self.source.stats()
+    }
 
     pub fn _get_pvs(&self) -> &Vec<PVString> {
         &self.pvs
@@ -241,60 +468,261 @@ impl DataLogger {
         &self.storage_data
     }
 
-    fn _test_redis(&mut self) {
-        let mut con = self.redis_client.get_connection().unwrap();
-
-        // test if redis is reachable and usable
-        let secret = rand::thread_rng().gen::<u32>();
-        let _: () = redis::cmd("SET").arg("ping").arg(secret).query(&mut con).unwrap();
-        let result: u32 = redis::cmd("GET").arg("ping").query(&mut con).unwrap();
-        assert_eq!(result, secret);
-        debug!("Redis test successful");
-        let _: () = redis::cmd("DEL").arg("ping").query(&mut con).unwrap();
-
-        // close the connection
-        let _: () = redis::cmd("QUIT").query(&mut con).unwrap();
-    }
-
-    fn _read_pv_data(&mut self) {
-        let mut data: Vec<u16> = Vec::new();
-        let readstart = Instant::now();
-        for i in 0..self.pvs.len() {
-            debug!("Reading data for: {}", self.pvs[i].voltage.name);
-            let mut tmp: Vec<u16> = self.ctx.read_holding_registers(self.pvs[i].voltage.address, self.pvs[i].voltage.length).unwrap();
-            self.pvs[i].voltage.time = chrono::Utc::now().timestamp_millis();
-            data.append(&mut tmp);
-            debug!("Reading data for: {}", self.pvs[i].current.name);
-            let mut tmp: Vec<u16> = self.ctx.read_holding_registers(self.pvs[i].current.address, self.pvs[i].current.length).unwrap();
-            self.pvs[i].current.time = chrono::Utc::now().timestamp_millis();
-            data.append(&mut tmp);
+    pub fn _get_pgs_data(&self) -> &Vec<PVSignal> {
+        &self.pgs_data
+    }
+
+    async fn _read_pv_data(&mut self) -> Result<(), ModbusError> {
+        let mut signals: Vec<PVSignal> = self.pvs.iter().flat_map(|x| [x.voltage.clone(), x.current.clone()]).collect();
+        if let Some(rd) = read_due(&mut signals, Category::Pv, &self.schedule, self.source.as_mut(), &self.planner, "PV".to_string()).await? {
+            self.read_durations.insert(Category::Pv, rd);
         }
-        let rd = readstart.elapsed();
-        let writestart = Instant::now();
-        for i in 0..self.pvs.len() {
-            match self.pvs[i].voltage.data {
-                PVSignalDataType::I16(_) => {
-                    self.pvs[i].voltage.data = PVSignalDataType::I16(data[i*2] as i16);
-                },
-                _ => {
-                    error!("Wrong data type for voltage: {:?}", self.pvs[i].voltage.data)
-                },
-            }
-            match self.pvs[i].current.data {
-                PVSignalDataType::I16(_) => {
-                    self.pvs[i].current.data = PVSignalDataType::I16(data[i*2+1] as i16);
-                },
-                _ => {
-                    error!("Wrong data type for current: {:?}", self.pvs[i].current.data)
-                },
-            }
+        for (pv, v) in self.pvs.iter_mut().zip(signals.chunks(2)) {
+            pv.voltage = v[0].clone();
+            pv.current = v[1].clone();
         }
-        let wd = writestart.elapsed();
-        info!("General Read: {}ms Write: {}", rd.as_millis(), wd.as_millis())
+        Ok(())
+    }
+
+    async fn _get_num_pvs(&mut self) -> Result<u16, ModbusError> {
+        let pvs: Vec<u16> = self.source.read_holding_registers(30071, 1).await?;
+        Ok(pvs[0])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+    use tokio_modbus::prelude::{Slave, SlaveContext};
+    use crate::capture::Replay;
+    use crate::connection::{Connection, Transport};
+    use crate::parser::{read_definitions, DEFAULT_DEFINITIONS};
+    use crate::planner::{DEFAULT_MAX_GAP, MAX_BLOCK_SIZE};
+    use crate::simulator::{Simulator, SimulatorConfig};
+    use crate::source::MemorySource;
+
+    fn logger(source: impl RegisterSource + 'static) -> DataLogger {
+        let profiles = Registry::load(DEFAULT_DEFINITIONS).unwrap();
+        let schedule = Schedule::new(profiles.base(), Duration::from_secs(90)).unwrap();
+        DataLogger::new(source, profiles, ReadPlanner::new(MAX_BLOCK_SIZE, DEFAULT_MAX_GAP), schedule, 10, Duration::ZERO)
+    }
+
+    /// Connection to a simulated inverter on localhost
+    async fn simulator(config: SimulatorConfig) -> Connection {
+        let defs = read_definitions(DEFAULT_DEFINITIONS).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let connection = Connection::new(Transport::Tcp(listener.local_addr().unwrap()), Slave(1), Duration::from_millis(500));
+        tokio::spawn(Simulator::new(&defs, 1, config).unwrap().serve(listener));
+        connection
+    }
+
+    async fn simulated(config: SimulatorConfig) -> DataLogger {
+        logger(simulator(config).await)
+    }
+
+    fn value(signals: &[PVSignal], name: &str) -> Option<f64> {
+        signals.iter().find(|s| s.name == name)?.scaled()
+    }
+
+    fn values(config: &[(&str, &str)]) -> Vec<(String, String)> {
+        config.iter().map(|(n, v)| (n.to_string(), v.to_string())).collect()
+    }
+
+    fn prepare(name: &str, value: f64) -> Result<PVSignal, WriteError> {
+        prepare_write(&read_definitions(DEFAULT_DEFINITIONS).unwrap(), name, value)
+    }
+
+    #[test]
+    fn applies_the_inverse_gain() {
+        let s = prepare("active_power_derating", 50.5).unwrap();
+        assert_eq!((s.address, encode_value(&s.data).unwrap()), (40125, vec![505]));
+        let s = prepare("active_power_derated_w", 70000.0).unwrap();
+        assert_eq!(encode_value(&s.data).unwrap(), vec![1, 4464]);
+        let s = prepare("qs_reactive_power_comp", -0.9).unwrap();
+        assert_eq!(encode_value(&s.data).unwrap(), vec![-900i16 as u16]);
+    }
+
+    #[test]
+    fn rejects_invalid_writes() {
+        assert!(matches!(prepare("nope", 1.0), Err(WriteError::UnknownSignal(_))));
+        // general and battery status
+        assert!(matches!(prepare("status", 1.0), Err(WriteError::Ambiguous(_))));
+        assert!(matches!(prepare("active_power", 1.0), Err(WriteError::NotWritable(_))));
+        assert!(matches!(prepare("active_power_derating", 100.1), Err(WriteError::OutOfRange(..))));
+        assert!(matches!(prepare("active_power_derating", -1.0), Err(WriteError::OutOfRange(..))));
+        // finer than the gain allows
+        assert!(matches!(prepare("active_power_derating", 50.55), Err(WriteError::OutOfRange(..))));
+        assert!(prepare("active_power_derating", 1.1).is_ok());
+        // within the definition but not representable in the register
+        assert!(matches!(prepare("active_power_derated_w", -5.0), Err(WriteError::OutOfRange(..))));
+    }
+
+    #[tokio::test]
+    async fn counts_simulated_strings() {
+        let mut logger = simulated(SimulatorConfig { pv_strings: 6, ..Default::default() }).await;
+        assert_eq!(logger._get_num_pvs().await.unwrap(), 6);
+    }
+
+    #[tokio::test]
+    async fn initializes_from_the_simulator() {
+        let mut logger = simulated(SimulatorConfig { pv_strings: 4, packs: vec![0, 1, 4], ..Default::default() }).await;
+        logger.init().await.unwrap();
+        assert_eq!(logger._get_pvs().len(), 4);
+        assert_eq!(logger.packs, vec![0, 1, 4]);
+        assert!(logger._get_storage_data().iter().any(|s| s.name == "pack4_soc"));
+        assert!(!logger._get_storage_data().iter().any(|s| s.name.starts_with("pack2_")));
+        assert_eq!(logger.read_device_id().await.unwrap(), "SUN2000-8KTL-M1");
+    }
+
+    #[tokio::test]
+    async fn reads_simulated_values() {
+        let config = SimulatorConfig {
+            values: values(&[("active_power", "5.5"), ("pv_1_voltage", "401.3"), ("pv_1_current", "-0.25"), ("grid_charge_cutoff_soc", "80"), ("meter_active_power", "-1200")]),
+            packs: vec![0],
+            ..Default::default()
+        };
+        let mut logger = simulated(config).await;
+        logger.init().await.unwrap();
+        logger.read_data().await.unwrap();
+        let general = logger._get_general_data();
+        assert_eq!(value(general, "active_power"), Some(5.5));
+        assert_eq!(value(general, "meter_active_power"), Some(-1200.0));
+        assert_eq!(value(general, "num_strings"), Some(2.0));
+        assert!(general.iter().all(|s| s.time > 0), "every signal is read in the first cycle");
+        let pv = &logger._get_pvs()[1];
+        assert_eq!((pv.voltage.scaled(), pv.current.scaled()), (Some(401.3), Some(-0.25)));
+        assert_eq!(value(logger._get_storage_data(), "pack0_soc"), Some(55.0));
+        assert_eq!(logger.write_signal("grid_charge_cutoff_soc", 90.0).await.unwrap().scaled(), Some(90.0));
+    }
+
+    #[tokio::test]
+    async fn skips_the_cycle_on_exceptions() {
+        let mut logger = simulated(SimulatorConfig { exceptions: vec![32085], ..Default::default() }).await;
+        logger.init().await.unwrap();
+        assert!(matches!(logger.read_data().await, Err(ModbusError::Exception(_))));
+        assert_eq!(logger.connection_stats().skipped_cycles, 1);
+        // an exception leaves the connection usable
+        assert_eq!(logger.connection_stats().connects, 1);
+        assert_eq!(logger._get_num_pvs().await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn reads_only_defined_registers() {
+        let strict = || SimulatorConfig { reject_undefined: true, ..Default::default() };
+        let mut exact = simulated(strict()).await;
+        exact.init().await.unwrap();
+        // looking for the meter and the battery packs runs into the missing ones
+        let detected = exact.connection_stats().exceptions;
+        exact.read_data().await.unwrap();
+        assert_eq!(exact.connection_stats().exceptions, detected);
+        // bridging gaps reads registers the inverter doesn't have
+        let mut bridging = logger(simulator(strict()).await);
+        bridging.planner = ReadPlanner::new(MAX_BLOCK_SIZE, 8);
+        bridging.init().await.unwrap();
+        assert!(matches!(bridging.read_data().await, Err(ModbusError::Exception(_))));
+    }
+
+    #[tokio::test]
+    async fn times_out_on_a_slow_inverter() {
+        let mut logger = simulated(SimulatorConfig { latency_ms: 2000, ..Default::default() }).await;
+        assert!(matches!(logger._get_num_pvs().await, Err(ModbusError::Io(_))));
+        assert_eq!(logger.connection_stats().io_errors, 1);
+    }
+
+    #[tokio::test]
+    async fn replays_a_capture() {
+        let path = std::env::temp_dir().join(format!("solar_getter_capture_{}.jsonl", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let _ = std::fs::remove_file(&path);
+        let config = SimulatorConfig { values: values(&[("active_power", "3.25")]), packs: vec![0, 1], ..Default::default() };
+        let capture = crate::capture::Capture::open(&path).unwrap();
+        let mut recorded = logger(simulator(config).await.with_capture(Some(capture)));
+        recorded.init().await.unwrap();
+        recorded.read_data().await.unwrap();
+
+        let mut replayed = logger(Connection::new(Transport::Replay(path.clone()), Slave(1), Duration::from_millis(500)));
+        replayed.init().await.unwrap();
+        replayed.read_data().await.unwrap();
+        // the same without a connection in between
+        let mut replay = Replay::load(&path).await.unwrap();
+        replay.set_slave(Slave(1));
+        let mut direct = logger(replay);
+        direct.init().await.unwrap();
+        direct.read_data().await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(replayed.packs, vec![0, 1]);
+        assert_eq!(value(replayed._get_general_data(), "active_power"), Some(3.25));
+        let values = |logger: &DataLogger| logger._get_general_data().iter().map(|s| (s.name.clone(), s.data.to_string())).collect::<Vec<_>>();
+        assert_eq!(values(&replayed), values(&recorded));
+        assert_eq!(values(&direct), values(&recorded));
+    }
+
+    #[tokio::test]
+    async fn reads_from_a_memory_source() {
+        let mut map = MemorySource::default();
+        map.set(30071, &[3]).set(32080, &[0xFFFF, 0xFF38]);
+        let mut logger = logger(map);
+        assert_eq!(logger._get_num_pvs().await.unwrap(), 3);
+        assert_eq!(logger.read_signal("active_power").await.unwrap().scaled(), Some(-0.2));
+        assert_eq!(logger.read_registers(30071, 1, false).await.unwrap(), vec![3]);
+        assert!(matches!(logger.read_registers(65535, 2, false).await, Err(ModbusError::Io(_))));
+        // nothing about the batteries in the map
+        assert!(matches!(logger.read_signal("charge_discharge_power").await, Err(WriteError::Modbus(ModbusError::Exception(_)))));
+        assert_eq!(logger.write_signal("active_power_derating", 50.5).await.unwrap().scaled(), Some(50.5));
+        assert_eq!(logger.connection_stats().exceptions, 1);
     }
 
-    fn _get_num_pvs(&mut self) -> u16 {
-        let pvs: Vec<u16> = self.ctx.read_holding_registers(30071, 1).unwrap();
-        return pvs[0];
+    fn has(signals: &[PVSignal], name: &str) -> bool {
+        signals.iter().any(|s| s.name == name)
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn picks_the_profile_of_the_model() {
+        let mut logger = simulated(SimulatorConfig { packs: vec![3], ..Default::default() }).await;
+        logger.init().await.unwrap();
+        assert_eq!(logger.profile, Some(Profile { series: Some(Series::M1), battery: true, meter: true }));
+        assert!(has(logger._get_general_data(), "c_volt") && has(logger._get_general_data(), "meter_active_power"));
+        assert!(has(logger._get_storage_data(), "soc"));
+
+        let config = SimulatorConfig { values: values(&[("model_ident", "SUN2000-5KTL-L1"), ("meter_status", "0")]), ..Default::default() };
+        let mut logger = simulated(config).await;
+        logger.init().await.unwrap();
+        assert_eq!(logger.profile, Some(Profile { series: Some(Series::L1), battery: false, meter: false }));
+        assert!(has(logger._get_general_data(), "a_volt") && !has(logger._get_general_data(), "c_volt"));
+        assert!(!has(logger._get_general_data(), "meter_active_power"));
+        assert!(logger._get_storage_data().is_empty());
+        logger.read_data().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn notices_a_meter_coming_back() {
+        let config = SimulatorConfig { values: values(&[("meter_status", "0")]), ..Default::default() };
+        let mut logger = simulated(config).await;
+        logger.pack_detect_interval = Duration::from_millis(1);
+        logger.init().await.unwrap();
+        assert!(!logger.profile.unwrap().meter);
+        assert!(has(logger._get_general_data(), "meter_status") && !has(logger._get_general_data(), "meter_active_power"));
+
+        logger.source.write_registers(METER_STATUS_ADDRESS, &[1]).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        logger.read_data().await.unwrap();
+        assert!(logger.profile.unwrap().meter);
+        assert!(has(logger._get_general_data(), "meter_active_power"));
+    }
+
+    #[tokio::test]
+    async fn falls_back_on_unknown_models() {
+        let config = SimulatorConfig { values: values(&[("model_ident", "SUN2000-60KTL-HV-D1")]), packs: vec![0], ..Default::default() };
+        let mut logger = simulated(config).await;
+        logger.init().await.unwrap();
+        assert_eq!(logger.profile, Some(Profile { series: None, battery: true, meter: true }));
+        assert_eq!(logger.definitions, *logger.profiles.base());
+
+        // an M0 can't have a battery, the packs aren't even looked for
+        let config = SimulatorConfig { values: values(&[("model_ident", "SUN2000-100KTL-M0")]), packs: vec![0], ..Default::default() };
+        let mut logger = simulated(config).await;
+        logger.init().await.unwrap();
+        assert_eq!((logger.profile.unwrap().battery, logger.packs.len()), (false, 0));
+    }
+}
@@ -0,0 +1,260 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::Arc;
+
+use chrono::{Local, TimeZone};
+
+use crate::parser::types::*;
+use crate::sinks::{Category, Sample};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    /// A bit of an alarm or state register got set
+    Raised,
+    /// A bit of an alarm or state register got cleared
+    Cleared,
+    /// A status or mode register switched to another code
+    Changed,
+}
+
+impl EventKind {
+    pub fn key(&self) -> &'static str {
+        match self {
+            EventKind::Raised => "raised",
+            EventKind::Cleared => "cleared",
+            EventKind::Changed => "changed",
+        }
+    }
+
+    pub fn from_key(key: &str) -> Option<EventKind> {
+        match key {
+            "raised" => Some(EventKind::Raised),
+            "cleared" => Some(EventKind::Cleared),
+            "changed" => Some(EventKind::Changed),
+            _ => None,
+        }
+    }
+}
+
+/// Something that happened between two cycles on a state or bitfield register
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event {
+    /// Unix millis of the read that saw the change
+    pub time: i64,
+    pub category: Category,
+    pub signal: String,
+    pub kind: EventKind,
+    /// Name of the bit for raised/cleared, the new state for changed
+    pub state: String,
+    /// State before a change
+    pub previous: Option<String>,
+}
+
+impl Event {
+    /// Fields as stored in the Redis stream
+    pub fn fields(&self) -> Vec<(&'static str, String)> {
+        let mut fields = vec![
+            ("time", self.time.to_string()),
+            ("category", self.category.key().to_string()),
+            ("signal", self.signal.clone()),
+            ("kind", self.kind.key().to_string()),
+            ("state", self.state.clone()),
+        ];
+        if let Some(previous) = &self.previous {
+            fields.push(("previous", previous.clone()));
+        }
+        fields
+    }
+
+    pub fn from_fields(fields: &HashMap<String, String>) -> Option<Event> {
+        Some(Event {
+            time: fields.get("time")?.parse().ok()?,
+            category: Category::from_key(fields.get("category")?)?,
+            signal: fields.get("signal")?.clone(),
+            kind: EventKind::from_key(fields.get("kind")?)?,
+            state: fields.get("state")?.clone(),
+            previous: fields.get("previous").cloned(),
+        })
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let time = Local.timestamp_millis_opt(self.time).single().map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string()).unwrap_or_else(|| self.time.to_string());
+        write!(f, "{} {} {} {}: ", time, self.category.key(), self.signal, self.kind.key())?;
+        match &self.previous {
+            Some(previous) => write!(f, "{} -> {}", previous, self.state),
+            None => write!(f, "{}", self.state),
+        }
+    }
+}
+
+/// Diffs the state and bitfield registers of every cycle against the
+/// previous one and keeps the most recent events around
+#[derive(Debug)]
+pub struct EventLog {
+    previous: HashMap<(Category, String), PVSignalDataType>,
+    history: VecDeque<Event>,
+    capacity: usize,
+    /// Events recorded since the start, including the ones dropped from the history
+    recorded: usize,
+}
+
+/// Events for a bitfield going from `old` to `new`, one per flipped bit
+fn bit_events(sample: &Sample, old: u16, new: u16, labels: &Arc<StateLabels>) -> Vec<Event> {
+    let event = |kind, state| Event {
+        time: sample.time,
+        category: sample.category,
+        signal: sample.name.clone(),
+        kind,
+        state,
+        previous: None,
+    };
+    let raised = PVSignalDataType::BITS(new & !old, labels.clone()).active_bits();
+    let cleared = PVSignalDataType::BITS(old & !new, labels.clone()).active_bits();
+    raised.into_iter().map(|b| event(EventKind::Raised, b))
+        .chain(cleared.into_iter().map(|b| event(EventKind::Cleared, b)))
+        .collect()
+}
+
+impl EventLog {
+    pub fn new(capacity: usize) -> EventLog {
+        EventLog {
+            previous: HashMap::new(),
+            history: VecDeque::with_capacity(capacity),
+            capacity,
+            recorded: 0,
+        }
+    }
+
+    /// Records what changed since the last call. The first value of a
+    /// register is not an event.
+    pub fn update(&mut self, samples: &[Sample]) -> Vec<Event> {
+        let mut events = Vec::new();
+        for s in samples.iter() {
+            if !matches!(s.raw, PVSignalDataType::ENUM(..) | PVSignalDataType::BITS(..)) {
+                continue;
+            }
+            let previous = match self.previous.insert((s.category, s.name.clone()), s.raw.clone()) {
+                Some(p) => p,
+                None => continue,
+            };
+            match (&previous, &s.raw) {
+                (PVSignalDataType::BITS(old, _), PVSignalDataType::BITS(new, labels)) => {
+                    events.append(&mut bit_events(s, *old, *new, labels));
+                }
+                (PVSignalDataType::ENUM(old, _), PVSignalDataType::ENUM(new, _)) if old != new => events.push(Event {
+                    time: s.time,
+                    category: s.category,
+                    signal: s.name.clone(),
+                    kind: EventKind::Changed,
+                    state: s.label.clone().unwrap_or_default(),
+                    previous: previous.label(),
+                }),
+                _ => {}
+            }
+        }
+        for e in events.iter() {
+            if self.history.len() == self.capacity {
+                self.history.pop_front();
+            }
+            self.history.push_back(e.clone());
+        }
+        self.recorded += events.len();
+        events
+    }
+
+    /// Recorded events, oldest first
+    pub fn history(&self) -> &VecDeque<Event> {
+        &self.history
+    }
+
+    pub fn recorded(&self) -> usize {
+        self.recorded
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(name: &str, data: PVSignalDataType, time: i64) -> Sample {
+        let signal = PVSignal {
+            data,
+            address: 32008,
+            length: 1,
+            name: name.to_string(),
+            unit: "".to_string(),
+            gain: 1,
+            time,
+        };
+        Sample::from_signal(Category::General, &signal)
+    }
+
+    fn labels() -> Arc<StateLabels> {
+        Arc::new([(0, "Standby: initializing".to_string()), (7, "Grid Loss".to_string()), (8, "Grid Undervoltage".to_string()), (0x0200, "On-grid".to_string())].into_iter().collect())
+    }
+
+    fn alarm(bits: u16, time: i64) -> Sample {
+        sample("alarm_1", PVSignalDataType::BITS(bits, labels()), time)
+    }
+
+    fn status(code: u16, time: i64) -> Sample {
+        sample("status", PVSignalDataType::ENUM(code, labels()), time)
+    }
+
+    #[test]
+    fn raises_and_clears_bits() {
+        let mut log = EventLog::new(10);
+        assert!(log.update(&[alarm(0, 1)]).is_empty());
+        let events = log.update(&[alarm(1 << 7 | 1 << 8, 2)]);
+        assert_eq!(events.iter().map(|e| (e.kind, e.state.as_str())).collect::<Vec<_>>(), vec![(EventKind::Raised, "Grid Loss"), (EventKind::Raised, "Grid Undervoltage")]);
+        let events = log.update(&[alarm(1 << 8, 3)]);
+        assert_eq!(events.len(), 1);
+        assert_eq!((events[0].kind, events[0].state.as_str(), events[0].time), (EventKind::Cleared, "Grid Loss", 3));
+        assert!(log.update(&[alarm(1 << 8, 4)]).is_empty());
+        assert_eq!(log.history().len(), 3);
+    }
+
+    #[test]
+    fn reports_state_changes() {
+        let mut log = EventLog::new(10);
+        assert!(log.update(&[status(0, 1)]).is_empty());
+        assert!(log.update(&[status(0, 2)]).is_empty());
+        let events = log.update(&[status(0x0200, 3)]);
+        assert_eq!(events, vec![Event {
+            time: 3,
+            category: Category::General,
+            signal: "status".to_string(),
+            kind: EventKind::Changed,
+            state: "On-grid".to_string(),
+            previous: Some("Standby: initializing".to_string()),
+        }]);
+    }
+
+    #[test]
+    fn keeps_the_latest_events() {
+        let mut log = EventLog::new(2);
+        log.update(&[alarm(0, 0)]);
+        for i in 1..=4 {
+            log.update(&[alarm(if i % 2 == 1 { 1 << 7 } else { 0 }, i)]);
+        }
+        let times: Vec<i64> = log.history().iter().map(|e| e.time).collect();
+        assert_eq!(times, vec![3, 4]);
+        assert_eq!(log.recorded(), 4);
+    }
+
+    #[test]
+    fn roundtrips_stream_fields() {
+        let event = Event {
+            time: 1700000000000,
+            category: Category::Storage,
+            signal: "working_mode".to_string(),
+            kind: EventKind::Changed,
+            state: "Maximise self consumption".to_string(),
+            previous: Some("Time of use (LUNA2000)".to_string()),
+        };
+        let fields: HashMap<String, String> = event.fields().into_iter().map(|(k, v)| (k.to_string(), v)).collect();
+        assert_eq!(Event::from_fields(&fields), Some(event));
+    }
+}
@@ -0,0 +1,249 @@
+use std::time::Instant;
+
+use async_trait::async_trait;
+
+use crate::config::ExportLimitConfig;
+use crate::datalogger::{WriteError, DataLogger};
+
+/// What the export limiter needs from an inverter, powers in watts
+#[async_trait]
+pub trait PowerControl: Send {
+    /// Maximum active power, the base of the percentage limit
+    async fn max_power_w(&mut self) -> Result<f64, WriteError>;
+    /// Power flowing into the grid at the meter, negative when importing
+    async fn export_w(&mut self) -> Result<f64, WriteError>;
+    /// Active power the inverter currently puts out
+    async fn output_w(&mut self) -> Result<f64, WriteError>;
+    async fn set_limit_percent(&mut self, percent: f64) -> Result<(), WriteError>;
+}
+
+async fn number(datalogger: &mut DataLogger, name: &str) -> Result<f64, WriteError> {
+    let signal = datalogger.read_signal(name).await?;
+    signal.scaled().ok_or_else(|| WriteError::Invalid(format!("{} is not a number", name)))
+}
+
+#[async_trait]
+impl PowerControl for DataLogger {
+    async fn max_power_w(&mut self) -> Result<f64, WriteError> {
+        Ok(number(self, "max_active_power").await? * 1000.0)
+    }
+
+    async fn export_w(&mut self) -> Result<f64, WriteError> {
+        number(self, "meter_active_power").await
+    }
+
+    async fn output_w(&mut self) -> Result<f64, WriteError> {
+        Ok(number(self, "active_power").await? * 1000.0)
+    }
+
+    async fn set_limit_percent(&mut self, percent: f64) -> Result<(), WriteError> {
+        self.write_signal("active_power_derating", percent).await.map(|_| ())
+    }
+}
+
+/// Closed loop keeping the export at the meter below `max_export_w` by
+/// adjusting the inverter's active power limit
+#[derive(Debug)]
+pub struct ExportLimiter {
+    config: ExportLimitConfig,
+    max_power_w: f64,
+    /// Limit written last, `None` until the first write
+    limit_w: Option<f64>,
+}
+
+impl ExportLimiter {
+    pub async fn new(config: &ExportLimitConfig, plant: &mut impl PowerControl) -> Result<ExportLimiter, WriteError> {
+        let max_power_w = plant.max_power_w().await?;
+        if max_power_w <= 0.0 {
+            return Err(WriteError::Invalid(format!("maximum active power is {} W", max_power_w)));
+        }
+        info!("Limiting export to {} W (inverter maximum {} W)", config.max_export_w, max_power_w);
+        Ok(ExportLimiter { config: config.clone(), max_power_w, limit_w: None })
+    }
+
+    /// Limit to aim for given the current meter and inverter readings.
+    /// Lowering happens at once so we stop exporting as fast as possible,
+    /// raising is ramped so a passing cloud doesn't make the limit jump.
+    fn target(&self, export_w: f64, output_w: f64) -> f64 {
+        let wanted = (output_w + self.config.max_export_w - export_w).clamp(0.0, self.max_power_w);
+        match self.limit_w {
+            Some(limit) if wanted > limit => wanted.min(limit + self.config.max_step_up_w),
+            _ => wanted,
+        }
+    }
+
+    async fn apply(&mut self, plant: &mut impl PowerControl, limit_w: f64) -> Result<(), WriteError> {
+        if let Some(current) = self.limit_w {
+            // don't wear out the inverter with tiny changes, but always go all the way to the extremes
+            let small = (limit_w - current).abs() < self.config.deadband_w;
+            let extreme = limit_w == 0.0 || limit_w == self.max_power_w;
+            if limit_w == current || (small && !extreme) {
+                return Ok(());
+            }
+        }
+        // the register has a resolution of 0.1 %
+        let percent = (limit_w / self.max_power_w * 1000.0).round() / 10.0;
+        plant.set_limit_percent(percent).await?;
+        debug!("Active power limit set to {} W ({} %)", limit_w, percent);
+        self.limit_w = Some(limit_w);
+        Ok(())
+    }
+
+    /// One round of reading the meter and adjusting the limit. Falls back to
+    /// the safe limit when the readings are unavailable.
+    pub async fn step(&mut self, plant: &mut impl PowerControl) -> Result<f64, WriteError> {
+        let readings = match plant.export_w().await {
+            Ok(export_w) => plant.output_w().await.map(|output_w| (export_w, output_w)),
+            Err(e) => Err(e),
+        };
+        match readings {
+            Ok((export_w, output_w)) => {
+                let limit = self.target(export_w, output_w);
+                self.apply(plant, limit).await?;
+                Ok(limit)
+            }
+            Err(e) => {
+                let fallback = self.config.fallback_limit_w.min(self.max_power_w);
+                warn!("Reading the meter failed: {}, falling back to {} W", e, fallback);
+                // the fallback always has to make it to the inverter
+                self.limit_w = None;
+                self.apply(plant, fallback).await?;
+                Err(e)
+            }
+        }
+    }
+
+    /// Lifts the limit when shutting down, otherwise the inverter would stay
+    /// curtailed without anyone adjusting it
+    pub async fn release(&mut self, plant: &mut impl PowerControl) -> Result<(), WriteError> {
+        plant.set_limit_percent(100.0).await?;
+        info!("Active power limit lifted");
+        self.limit_w = None;
+        Ok(())
+    }
+
+    /// Runs steps every `interval_secs` until `deadline`
+    pub async fn run_until(&mut self, plant: &mut impl PowerControl, deadline: Instant) {
+        while Instant::now() < deadline {
+            if let Err(e) = self.step(plant).await {
+                warn!("Export limiter step failed: {}", e);
+            }
+            tokio::time::sleep(self.config.interval().min(deadline.saturating_duration_since(Instant::now()))).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Inverter with a meter, the PV power it could produce and the house load
+    struct FakeInverter {
+        max_power_w: f64,
+        available_w: f64,
+        load_w: f64,
+        limit_percent: f64,
+        meter_fails: bool,
+        writes: Vec<f64>,
+    }
+
+    impl FakeInverter {
+        fn new(available_w: f64, load_w: f64) -> FakeInverter {
+            FakeInverter { max_power_w: 8000.0, available_w, load_w, limit_percent: 100.0, meter_fails: false, writes: Vec::new() }
+        }
+    }
+
+    #[async_trait]
+    impl PowerControl for FakeInverter {
+        async fn max_power_w(&mut self) -> Result<f64, WriteError> {
+            Ok(self.max_power_w)
+        }
+
+        async fn export_w(&mut self) -> Result<f64, WriteError> {
+            if self.meter_fails {
+                return Err(WriteError::Invalid("meter offline".to_string()));
+            }
+            Ok(self.output_w().await? - self.load_w)
+        }
+
+        async fn output_w(&mut self) -> Result<f64, WriteError> {
+            Ok(self.available_w.min(self.max_power_w * self.limit_percent / 100.0))
+        }
+
+        async fn set_limit_percent(&mut self, percent: f64) -> Result<(), WriteError> {
+            self.limit_percent = percent;
+            self.writes.push(percent);
+            Ok(())
+        }
+    }
+
+    fn config(max_export_w: f64) -> ExportLimitConfig {
+        ExportLimitConfig { enabled: true, max_export_w, max_step_up_w: 500.0, deadband_w: 50.0, fallback_limit_w: 1000.0, ..Default::default() }
+    }
+
+    #[tokio::test]
+    async fn holds_export_below_threshold() {
+        let mut inverter = FakeInverter::new(6000.0, 1500.0);
+        let mut limiter = ExportLimiter::new(&config(0.0), &mut inverter).await.unwrap();
+        limiter.step(&mut inverter).await.unwrap();
+        assert!(inverter.export_w().await.unwrap() <= 10.0);
+        // load drops, export must be cut right away
+        inverter.load_w = 500.0;
+        limiter.step(&mut inverter).await.unwrap();
+        assert!(inverter.export_w().await.unwrap() <= 10.0);
+        assert!(inverter.export_w().await.unwrap() >= -10.0);
+    }
+
+    #[tokio::test]
+    async fn ramps_up_slowly() {
+        let mut inverter = FakeInverter::new(6000.0, 1000.0);
+        let mut limiter = ExportLimiter::new(&config(200.0), &mut inverter).await.unwrap();
+        limiter.step(&mut inverter).await.unwrap();
+        // the load goes up, the limit follows in steps of at most 500 W
+        inverter.load_w = 4000.0;
+        let mut limits = Vec::new();
+        for _ in 0..10 {
+            limits.push(limiter.step(&mut inverter).await.unwrap());
+        }
+        for w in limits.windows(2) {
+            assert!(w[1] - w[0] <= 500.0);
+        }
+        assert!((limits.last().unwrap() - 4200.0).abs() < 1.0);
+        assert!(inverter.export_w().await.unwrap() <= 200.0 + 10.0);
+    }
+
+    #[tokio::test]
+    async fn skips_small_changes() {
+        let mut inverter = FakeInverter::new(3000.0, 2000.0);
+        let mut limiter = ExportLimiter::new(&config(0.0), &mut inverter).await.unwrap();
+        limiter.step(&mut inverter).await.unwrap();
+        limiter.step(&mut inverter).await.unwrap();
+        inverter.load_w = 2020.0;
+        limiter.step(&mut inverter).await.unwrap();
+        assert_eq!(inverter.writes.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn falls_back_when_the_meter_fails() {
+        let mut inverter = FakeInverter::new(6000.0, 5000.0);
+        let mut limiter = ExportLimiter::new(&config(0.0), &mut inverter).await.unwrap();
+        limiter.step(&mut inverter).await.unwrap();
+        inverter.meter_fails = true;
+        assert!(limiter.step(&mut inverter).await.is_err());
+        assert_eq!(inverter.limit_percent, 12.5);
+        // recovers once the meter is back, ramping up from the fallback
+        inverter.meter_fails = false;
+        assert_eq!(limiter.step(&mut inverter).await.unwrap(), 1500.0);
+    }
+
+    #[tokio::test]
+    async fn lifts_the_limit_when_stopping() {
+        let mut inverter = FakeInverter::new(6000.0, 0.0);
+        let mut limiter = ExportLimiter::new(&config(0.0), &mut inverter).await.unwrap();
+        limiter.step(&mut inverter).await.unwrap();
+        assert_eq!(inverter.limit_percent, 0.0);
+        limiter.release(&mut inverter).await.unwrap();
+        assert_eq!(inverter.limit_percent, 100.0);
+        assert_eq!(inverter.output_w().await.unwrap(), 6000.0);
+    }
+}
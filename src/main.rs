@@ -1,77 +1,62 @@
-use std::time::{Duration,Instant};
-use std::thread;
-use std::env;
+use std::process;
 
-use tokio_modbus::prelude::*;
+use clap::Parser;
+
+use cli::{Cli, Command};
+use config::Config;
 
 #[macro_use]
 extern crate log;
 
+mod battery;
+mod capture;
+mod cli;
+mod config;
+mod connection;
 mod datalogger;
+mod events;
+mod export_limit;
 mod parser;
-
-struct ConnectionData {
-    inverter_ip: String,
-    redis_ip: String,
-}
-
-fn main() {
-    env_logger::init();
-
-    info!("Connecting to slave");
-
-    let mut condata = ConnectionData{
-        inverter_ip:"192.168.178.83:502".to_string(),
-        redis_ip:"redis://192.168.178.109/".to_string()
-    };
-
-    match env::var("INV_IP") {
-        Ok(v) => condata.inverter_ip = v,
-        Err(_) => warn!("Environment variables not configured, using default"),
-    };
-
-    match env::var("RD_IP") {
-        Ok(v) => condata.redis_ip = v,
-        Err(_) => warn!("Environment variables not configured, using default"),
+mod planner;
+mod profiles;
+mod registers;
+mod schedule;
+mod simulator;
+mod sinks;
+mod source;
+
+#[tokio::main]
+async fn main() {
+    let args = Cli::parse();
+    // broken definitions would already fail loading the config
+    if let Some(Command::LintDefinitions { files }) = &args.command {
+        cli::lint_definitions(files);
     }
+    let mut config = match Config::load(args.config.as_deref()) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
 
-    
-    let mut ctx = sync::tcp::connect_slave(condata.inverter_ip.parse().unwrap(), Slave(1)).unwrap();
-    let client = redis::Client::open(condata.redis_ip).unwrap();
-    
-    debug!("sleeping 1 second to make sure the slave is ready");
-
-    thread::sleep(Duration::from_secs(1));
-
-    info!("Trying to read device id from slave");
-
-    // Read the device id to make sure everything is working as expected.
-    let did: Vec<u16> = ctx.read_holding_registers(30000, 15).unwrap();
-
-    // each register contains 2 chars
-    let mut text = String::new();
-    for i in 0..did.len() {
-        text.push((did[i] >> 8) as u8 as char);
-        text.push((did[i] & 0xFF) as u8 as char);
+    if args.capture.is_some() {
+        config.inverter.capture = args.capture.clone();
     }
 
-    info!("Device ID: {}", text);
-
-    let mut datalogger = datalogger::DataLogger::new(ctx, client);
-    datalogger.init();
-
-    loop {
-        info!("Starting new gathering cycle");
-        let readstart = Instant::now();
-        datalogger.read_data();
-        let readdur = readstart.elapsed();
-        info!("Reading Registers took: {}s", readdur.as_secs());
-        let writestart = Instant::now();
-        datalogger.send_data("test_alt".to_string());
-        let writedur = writestart.elapsed();
-        info!("Writing to Database took: {}s", writedur.as_secs());
-
-        thread::sleep(Duration::from_secs(90));
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(&config.log_level)).init();
+
+    let device = || cli::device(&config, args.device.as_deref());
+    match args.command.unwrap_or(Command::Run) {
+        Command::Run => cli::run(&config).await,
+        Command::ReadOnce => cli::read_once(&config, &device()).await,
+        Command::DumpRegisters { start, len, input } => cli::dump_registers(&config, &device(), start, len, input).await,
+        Command::Info => cli::info(&config, &device()).await,
+        Command::Write { signal, value } => cli::write(&config, &device(), &signal, value).await,
+        Command::Battery { command } => cli::battery(&config, &device(), &command).await,
+        Command::Events { count, watch } => cli::events(&config, &device(), count, watch).await,
+        Command::CheckConfig => cli::check_config(&config, args.config.as_deref().unwrap_or(config::DEFAULT_CONFIG)),
+        Command::LintDefinitions { .. } => unreachable!("Linting doesn't load the config"),
+        Command::Simulate { listen, config: simulator } => cli::simulate(&device(), &listen, simulator).await,
     }
-
 }
@@ -1,22 +1,63 @@
 use std::fs;
+use std::io;
+use std::path::Path;
 use self::types::*;
 
+pub mod lint;
 pub mod types;
 
-fn read_definitions() -> Root {
-    let content = fs::read_to_string("./definitions.json").expect("Unable to read file");
-    return serde_json::from_str(&content).expect("Invalid JSON supplied");
+pub const DEFAULT_DEFINITIONS: &str = "./definitions.json";
+/// How deep `extends` may go, guards against loops
+const MAX_EXTENDS: usize = 8;
+
+/// Loads definitions, on top of the ones they `extend` if any
+pub fn read_definitions(path: &str) -> io::Result<Root> {
+    read_extended(Path::new(path), 0)
+}
+
+fn read_extended(path: &Path, depth: usize) -> io::Result<Root> {
+    let content = fs::read_to_string(path)?;
+    let defs: Root = serde_json::from_str(&content)?;
+    let Some(parent) = defs.extends.clone() else {
+        return Ok(defs);
+    };
+    if depth >= MAX_EXTENDS {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} extends too many definitions, is there a loop?", path.display())));
+    }
+    // relative to the extending file
+    let parent = path.parent().unwrap_or(Path::new(".")).join(parent);
+    Ok(merge(read_extended(&parent, depth + 1)?, defs))
 }
 
-fn filter_category(data: &Vec<Const>, category: u8) -> Vec<&Const> {
-    let filtered = data.iter().filter(|x| x.category == category).collect();
-    return filtered;
+/// `child` on top of `base`: its signals replace those with the same name
+/// and category, the ones it `exclude`s are dropped
+fn merge(mut base: Root, child: Root) -> Root {
+    base.const_field.retain(|c| !child.exclude.contains(&c.name));
+    for c in child.const_field {
+        match base.const_field.iter_mut().find(|b| b.name == c.name && b.category == c.category) {
+            Some(b) => *b = c,
+            None => base.const_field.push(c),
+        }
+    }
+    base.groups.extend(child.groups);
+    if child.pv_group.is_some() {
+        base.pv_group = child.pv_group;
+    }
+    if !child.scheme.bat.is_empty() {
+        base.scheme = child.scheme;
+    }
+    base.extends = None;
+    base.exclude.clear();
+    base
 }
 
-pub fn gen_batdata(base_addr: u16, ident: u8) -> Vec<PVSignal> {
+fn filter_category(data: &[Const], category: u8) -> Vec<&Const> {
+    data.iter().filter(|x| x.category == category).collect()
+}
+
+pub fn gen_batdata(defs: &Root, base_addr: u16, ident: u8) -> Vec<PVSignal> {
     let mut out = Vec::new();
-    let defs = read_definitions();
-    let scheme = defs.scheme.bat;
+    let scheme = &defs.scheme.bat;
 
     for i in 0..scheme.len() {
         let b = scheme.get(i).expect("uh oh");
@@ -30,18 +71,14 @@ pub fn gen_batdata(base_addr: u16, ident: u8) -> Vec<PVSignal> {
             gain: b.gain,
             time: 0
         };
-        match b.dtype.as_str() {
-            "U16" => signal.data = PVSignalDataType::U16(0),
-            "U32" => signal.data = PVSignalDataType::U32(0),
-            "I16" => signal.data = PVSignalDataType::I16(0),
-            "I32" => signal.data = PVSignalDataType::I32(0),
-            "STR" => signal.data = PVSignalDataType::STR("".to_string()),
-            _ => continue,
+        match PVSignalDataType::from_dtype(&b.dtype, &b.labels) {
+            Some(data) => signal.data = data,
+            None => continue,
         }
         out.push(signal);
     }
 
-    return out;
+    out
 }
 
 pub fn gen_pvdata(num_pvs: u8) -> Vec<PVString> {
@@ -69,39 +106,179 @@ pub fn gen_pvdata(num_pvs: u8) -> Vec<PVString> {
         };
         pvs.push(pv);
     }
-    return pvs;
+    pvs
 }
 
-pub fn gen_constdata(category: u8) -> Vec<PVSignal> {
-    let defs = read_definitions();
-    let mut signals = Vec::new();
-    for c in filter_category(&defs.const_field, category) {
-        let mut signal = PVSignal {
-            data: PVSignalDataType::UNK(0),
-            address: c.addr,
-            length: c.len,
-            name: c.name.to_string(),
-            unit: c.unit.to_string(),
-            gain: c.gain,
-            time: 0
-        };
-        match c.dtype.as_str() {
-            "U16" => signal.data = PVSignalDataType::U16(0),
-            "U32" => signal.data = PVSignalDataType::U32(0),
-            "I16" => signal.data = PVSignalDataType::I16(0),
-            "I32" => signal.data = PVSignalDataType::I32(0),
-            "STR" => signal.data = PVSignalDataType::STR("".to_string()),
-            _ => continue,
-        }
-        signals.push(signal);
+/// Signal for a definition, `None` for dtypes we don't know
+pub fn gen_signal(c: &Const) -> Option<PVSignal> {
+    Some(PVSignal {
+        data: PVSignalDataType::from_dtype(&c.dtype, &c.labels)?,
+        address: c.addr,
+        length: c.len,
+        name: c.name.to_string(),
+        unit: c.unit.to_string(),
+        gain: c.gain,
+        time: 0
+    })
+}
+
+pub fn gen_constdata(defs: &Root, category: u8) -> Vec<PVSignal> {
+    filter_category(&defs.const_field, category).into_iter().filter_map(gen_signal).collect()
+}
+
+/// Registers in the 3xxxx range describe the device itself (model, string
+/// count, rated powers) and never change at runtime
+pub fn is_static(address: u16) -> bool {
+    (30000..32000).contains(&address)
+}
+
+pub fn gen_staticdata(defs: &Root) -> Vec<PVSignal> {
+    gen_constdata(defs, 0).into_iter().filter(|x| is_static(x.address)).collect()
+}
+
+/// Battery units a SUN2000 can manage, each with up to `PACKS_PER_UNIT` packs
+pub const BATTERY_UNITS: u8 = 2;
+pub const PACKS_PER_UNIT: u8 = 3;
+/// Serial number of each battery unit, empty when there is none
+pub const UNIT_SN_ADDRESSES: [u16; BATTERY_UNITS as usize] = [37052, 37700];
+
+/// Registers of one battery pack, the offsets of `scheme.bat` are within them
+pub const PACK_REGISTERS: u16 = 42;
+
+/// First register of a battery pack. Packs are numbered across units, 0-2
+/// belong to unit 1 and 3-5 to unit 2, their blocks follow each other.
+pub fn pack_address(ident: u8) -> u16 {
+    38200 + PACK_REGISTERS * ident as u16
+}
+
+/// Storage values plus the ones of every pack in `packs`
+pub fn gen_storagedata(defs: &Root, packs: &[u8]) -> Vec<PVSignal> {
+    let mut signals = gen_constdata(defs, 2);
+    for &ident in packs {
+        signals.append(&mut gen_batdata(defs, pack_address(ident), ident));
     }
-    return signals;
+    signals
 }
 
-pub fn gen_storagedata() -> Vec<PVSignal> {
-    let mut signals = gen_constdata(2);
-    signals.append(&mut gen_batdata(38200, 0));
-    signals.append(&mut gen_batdata(38242, 1));
-    signals.append(&mut gen_batdata(38284, 2));
-    return signals;
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::registers::decode_value;
+
+    /// Every signal with a gain from definitions.json, battery packs included
+    fn numeric_signals() -> Vec<PVSignal> {
+        let defs = read_definitions(DEFAULT_DEFINITIONS).unwrap();
+        let mut signals = gen_constdata(&defs, 0);
+        signals.append(&mut gen_storagedata(&defs, &[0, 1, 2, 3, 4, 5]));
+        signals.append(&mut gen_constdata(&defs, 1));
+        signals.append(&mut gen_constdata(&defs, 3));
+        signals.into_iter().filter(|s| s.scaled().is_some()).collect()
+    }
+
+    /// Register words holding `value` in the signal's data type
+    fn encode(template: &PVSignalDataType, value: i32) -> Vec<u16> {
+        match template {
+            PVSignalDataType::U32(_) | PVSignalDataType::I32(_) => vec![(value as u32 >> 16) as u16, value as u16],
+            _ => vec![value as u16],
+        }
+    }
+
+    fn decoded(signal: &PVSignal, value: i32) -> PVSignal {
+        PVSignal { data: decode_value(&signal.data, &encode(&signal.data, value)), ..signal.clone() }
+    }
+
+    #[test]
+    fn gains_are_positive() {
+        for s in numeric_signals() {
+            assert!(s.gain > 0, "{} has a gain of 0", s.name);
+        }
+    }
+
+    #[test]
+    fn gain_scales_to_one() {
+        for s in numeric_signals() {
+            assert_eq!(decoded(&s, s.gain as i32).scaled(), Some(1.0), "{}", s.name);
+        }
+    }
+
+    #[test]
+    fn signed_values_scale_negative() {
+        for s in numeric_signals() {
+            if matches!(s.data, PVSignalDataType::I16(_) | PVSignalDataType::I32(_)) {
+                assert_eq!(decoded(&s, -(s.gain as i32)).scaled(), Some(-1.0), "{}", s.name);
+            }
+        }
+        let active_power = numeric_signals().into_iter().find(|s| s.name == "active_power").unwrap();
+        assert_eq!(decoded(&active_power, -1500).scaled(), Some(-1.5));
+    }
+
+    fn general(name: &str) -> PVSignal {
+        gen_constdata(&read_definitions(DEFAULT_DEFINITIONS).unwrap(), 0).into_iter().find(|s| s.name == name).unwrap()
+    }
+
+    #[test]
+    fn names_states() {
+        let status = general("status");
+        assert_eq!(decoded(&status, 0x0200).data.label().as_deref(), Some("On-grid"));
+        assert_eq!(decoded(&status, 0xA000).data.label().as_deref(), Some("Standby: no irradiation"));
+        assert_eq!(decoded(&status, 0x1234).data.label().as_deref(), Some("Unknown (0x1234)"));
+        assert_eq!(decoded(&status, 0x0200).scaled(), None);
+    }
+
+    #[test]
+    fn names_bits() {
+        let alarm = general("alarm_1");
+        assert_eq!(decoded(&alarm, 0).data.label().as_deref(), Some("None"));
+        assert_eq!(decoded(&alarm, 1 << 7).data.label().as_deref(), Some("Grid Loss"));
+        assert_eq!(decoded(&alarm, 1 << 8 | 1).data.active_bits(), vec!["High String Input Voltage", "Grid Undervoltage"]);
+        let state = general("state_3");
+        assert_eq!(decoded(&state, 1 << 5).data.label().as_deref(), Some("bit 5"));
+    }
+
+    #[test]
+    fn generates_detected_packs_only() {
+        let defs = read_definitions(DEFAULT_DEFINITIONS).unwrap();
+        assert_eq!(gen_storagedata(&defs, &[]).len(), gen_constdata(&defs, 2).len());
+        let signals = gen_storagedata(&defs, &[1, 4]);
+        let sn: Vec<(&str, u16)> = signals.iter().filter(|s| s.name.ends_with("_sn")).map(|s| (s.name.as_str(), s.address)).collect();
+        assert_eq!(sn, vec![("pack1_sn", 38242), ("pack4_sn", 38368)]);
+        assert_eq!(pack_address(2), 38284);
+        assert_eq!(pack_address(3), 38326);
+    }
+
+    #[test]
+    fn text_is_not_scaled() {
+        let model = gen_staticdata(&read_definitions(DEFAULT_DEFINITIONS).unwrap()).into_iter().find(|s| s.name == "model_ident").unwrap();
+        assert_eq!(model.scaled(), None);
+    }
+
+    #[test]
+    fn extends_definitions() {
+        let base = Root {
+            const_field: vec![
+                Const { dtype: "U16".to_string(), addr: 1, len: 1, gain: 1, name: "a".to_string(), ..Default::default() },
+                Const { dtype: "U16".to_string(), addr: 2, len: 1, gain: 1, name: "b".to_string(), ..Default::default() },
+                Const { dtype: "U16".to_string(), addr: 3, len: 1, gain: 1, name: "c".to_string(), ..Default::default() },
+            ],
+            groups: [("fast".to_string(), 5)].into(),
+            ..Default::default()
+        };
+        let child: Root = serde_json::from_str(r#"{
+            "extends": "definitions.json",
+            "exclude": ["b"],
+            "const": [
+                {"dtype": "I16", "addr": 3, "len": 1, "gain": 10, "name": "c", "unit": "V", "category": 0},
+                {"dtype": "U16", "addr": 4, "len": 1, "gain": 1, "name": "d", "unit": "", "category": 0}
+            ],
+            "groups": {"slow": 60}
+        }"#).unwrap();
+        let merged = merge(base, child);
+        let names: Vec<(&str, u16)> = merged.const_field.iter().map(|c| (c.name.as_str(), c.gain)).collect();
+        assert_eq!(names, vec![("a", 1), ("c", 10), ("d", 1)]);
+        assert_eq!(merged.groups.len(), 2);
+        assert_eq!(merged.extends, None);
+
+        let l1 = read_definitions("./definitions.l1.json").unwrap();
+        assert_eq!(l1.const_field.len() + 6, read_definitions(DEFAULT_DEFINITIONS).unwrap().const_field.len());
+    }
+}
@@ -0,0 +1,265 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use serde_json::Value;
+
+use super::types::*;
+use super::{read_definitions, PACK_REGISTERS};
+
+const ROOT_KEYS: &[&str] = &["extends", "exclude", "const", "groups", "pvGroup", "scheme"];
+const CONST_KEYS: &[&str] = &["dtype", "addr", "len", "gain", "name", "unit", "category", "labels", "writable", "min", "max", "group", "interval"];
+const BAT_KEYS: &[&str] = &["dtype", "addr", "len", "gain", "name", "unit", "labels", "group", "interval"];
+/// General, PGS, storage and the battery settings that are only written
+const CATEGORIES: u8 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    /// The definitions can't be used like this
+    Error,
+    /// Works, but probably not as intended
+    Warning,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Finding {
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.severity {
+            Severity::Error => write!(f, "error: {}", self.message),
+            Severity::Warning => write!(f, "warning: {}", self.message),
+        }
+    }
+}
+
+fn error(message: String) -> Finding {
+    Finding { severity: Severity::Error, message }
+}
+
+fn warning(message: String) -> Finding {
+    Finding { severity: Severity::Warning, message }
+}
+
+/// Registers a dtype takes up, `None` for any length
+fn dtype_len(dtype: &str) -> Option<Option<u16>> {
+    match dtype {
+        "U16" | "I16" | "ENUM" | "BITS" => Some(Some(1)),
+        "U32" | "I32" => Some(Some(2)),
+        "STR" | "UNK" => Some(None),
+        _ => None,
+    }
+}
+
+/// Checks one signal on its own
+fn lint_signal(what: &str, dtype: &str, len: u16, gain: u16, labels: &StateLabels, findings: &mut Vec<Finding>) {
+    match dtype_len(dtype) {
+        None => findings.push(error(format!("{} has the unknown dtype {}", what, dtype))),
+        Some(Some(expected)) if len != expected => findings.push(error(format!("{} is {} but has a len of {} instead of {}", what, dtype, len, expected))),
+        Some(_) if len == 0 => findings.push(error(format!("{} has a len of 0", what))),
+        Some(_) => {}
+    }
+    if gain == 0 {
+        findings.push(error(format!("{} has a gain of 0", what)));
+    }
+    if !labels.is_empty() && dtype != "ENUM" && dtype != "BITS" {
+        findings.push(warning(format!("{} has labels, which are only used by ENUM and BITS", what)));
+    }
+}
+
+/// Reports every pair of signals sharing registers, `ranges` are (name, first register, len).
+/// Two names for exactly the same registers read the same value and are only a warning.
+fn lint_overlaps(mut ranges: Vec<(String, u32, u32)>, findings: &mut Vec<Finding>) {
+    ranges.sort_by_key(|(_, addr, _)| *addr);
+    for (i, (name, addr, len)) in ranges.iter().enumerate() {
+        for (other, other_addr, other_len) in ranges[i + 1..].iter().take_while(|(_, a, _)| *a < addr + len) {
+            if (addr, len) == (other_addr, other_len) {
+                findings.push(warning(format!("{} and {} both read {}-{}", name, other, addr, addr + len - 1)));
+                continue;
+            }
+            findings.push(error(format!(
+                "{} ({}-{}) overlaps {} ({}-{})",
+                name, addr, addr + len - 1, other, other_addr, other_addr + other_len - 1
+            )));
+        }
+    }
+}
+
+/// Checks definitions for problems that would silently give wrong or no values
+pub fn lint(defs: &Root) -> Vec<Finding> {
+    let mut findings = Vec::new();
+    let mut names: HashMap<&str, Vec<u8>> = HashMap::new();
+    for c in defs.const_field.iter() {
+        let what = format!("{} (category {})", c.name, c.category);
+        lint_signal(&what, &c.dtype, c.len, c.gain, &c.labels, &mut findings);
+        if c.category >= CATEGORIES {
+            findings.push(error(format!("{} has the unknown category {}", c.name, c.category)));
+        }
+        let categories = names.entry(&c.name).or_default();
+        if categories.contains(&c.category) {
+            findings.push(error(format!("{} is defined more than once in category {}", c.name, c.category)));
+        }
+        categories.push(c.category);
+    }
+    let mut shared: Vec<(&&str, &Vec<u8>)> = names.iter().filter(|(_, c)| c.iter().any(|&x| x != c[0])).collect();
+    shared.sort();
+    for (name, categories) in shared {
+        findings.push(warning(format!("{} is used in categories {:?}, it can't be read or written by name", name, categories)));
+    }
+    lint_overlaps(defs.const_field.iter().map(|c| (c.name.clone(), c.addr as u32, c.len.max(1) as u32)).collect(), &mut findings);
+
+    let mut seen = Vec::new();
+    for b in defs.scheme.bat.iter() {
+        let what = format!("pack {}", b.name);
+        lint_signal(&what, &b.dtype, b.len, b.gain, &b.labels, &mut findings);
+        if b.addr + b.len > PACK_REGISTERS {
+            findings.push(error(format!("{} extends past the {} registers of a pack", what, PACK_REGISTERS)));
+        }
+        if seen.contains(&&b.name) {
+            findings.push(error(format!("{} is defined more than once", what)));
+        }
+        seen.push(&b.name);
+    }
+    lint_overlaps(defs.scheme.bat.iter().map(|b| (format!("pack {}", b.name), b.addr as u32, b.len.max(1) as u32)).collect(), &mut findings);
+    findings
+}
+
+/// Keys of `value` that aren't in `known`
+fn unknown_keys<'a>(value: &'a Value, known: &[&str]) -> Vec<&'a str> {
+    match value.as_object() {
+        Some(map) => map.keys().map(|k| k.as_str()).filter(|k| !known.contains(k)).collect(),
+        None => Vec::new(),
+    }
+}
+
+/// Fields the parser would ignore, most likely typos
+fn lint_keys(file: &Path, json: &Value, findings: &mut Vec<Finding>) {
+    for key in unknown_keys(json, ROOT_KEYS) {
+        findings.push(error(format!("{}: unknown key {}", file.display(), key)));
+    }
+    let entries = |key: &str| json.get(key).and_then(|v| v.as_array()).cloned().unwrap_or_default();
+    for c in entries("const") {
+        for key in unknown_keys(&c, CONST_KEYS) {
+            findings.push(error(format!("{}: {} has the unknown field {}", file.display(), c["name"], key)));
+        }
+    }
+    let bat = json.get("scheme").and_then(|s| s.get("bat")).and_then(|v| v.as_array()).cloned().unwrap_or_default();
+    for b in bat {
+        for key in unknown_keys(&b, BAT_KEYS) {
+            findings.push(error(format!("{}: pack {} has the unknown field {}", file.display(), b["name"], key)));
+        }
+    }
+}
+
+/// Lints the definitions at `path` together with the ones they extend,
+/// also checking every file for fields the parser would ignore
+pub fn lint_file(path: &str) -> io::Result<Vec<Finding>> {
+    let mut findings = Vec::new();
+    let mut file = Path::new(path).to_path_buf();
+    // `read_definitions` below stops loops
+    for _ in 0..super::MAX_EXTENDS {
+        let json: Value = serde_json::from_str(&fs::read_to_string(&file)?)?;
+        lint_keys(&file, &json, &mut findings);
+        match json.get("extends").and_then(|e| e.as_str()) {
+            Some(parent) => file = file.parent().unwrap_or(Path::new(".")).join(parent),
+            None => break,
+        }
+    }
+    findings.append(&mut lint(&read_definitions(path)?));
+    Ok(findings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::DEFAULT_DEFINITIONS;
+
+    fn signal(name: &str, dtype: &str, addr: u16, len: u16, category: u8) -> Const {
+        Const { dtype: dtype.to_string(), addr, len, gain: 1, name: name.to_string(), category, ..Default::default() }
+    }
+
+    fn errors(defs: &Root) -> Vec<String> {
+        lint(defs).into_iter().filter(|f| f.severity == Severity::Error).map(|f| f.message).collect()
+    }
+
+    #[test]
+    fn shipped_definitions_have_no_errors() {
+        for path in [DEFAULT_DEFINITIONS, "./definitions.l1.json"] {
+            let findings = lint_file(path).unwrap();
+            assert!(findings.iter().all(|f| f.severity == Severity::Warning), "{:?}", findings);
+        }
+    }
+
+    #[test]
+    fn finds_overlaps_and_duplicates() {
+        let defs = Root {
+            const_field: vec![
+                signal("pmc_active_power", "I32", 37113, 2, 0),
+                signal("meter_active_power", "I32", 37113, 2, 0),
+                signal("meter_pa_power", "I32", 37114, 2, 0),
+                signal("status", "ENUM", 37000, 1, 2),
+                signal("status", "ENUM", 32089, 1, 0),
+                signal("temp", "I16", 32087, 1, 0),
+                signal("temp", "I16", 32088, 1, 0),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(errors(&defs), vec![
+            "temp is defined more than once in category 0",
+            "pmc_active_power (37113-37114) overlaps meter_pa_power (37114-37115)",
+            "meter_active_power (37113-37114) overlaps meter_pa_power (37114-37115)",
+        ]);
+        let warnings: Vec<String> = lint(&defs).into_iter().filter(|f| f.severity == Severity::Warning).map(|f| f.message).collect();
+        assert_eq!(warnings, vec![
+            "status is used in categories [2, 0], it can't be read or written by name",
+            "pmc_active_power and meter_active_power both read 37113-37114",
+        ]);
+    }
+
+    #[test]
+    fn checks_types() {
+        let mut zero_gain = signal("efficiency", "U16", 32086, 1, 0);
+        zero_gain.gain = 0;
+        let defs = Root {
+            const_field: vec![
+                signal("active_power", "I32", 32080, 1, 0),
+                signal("model_ident", "STR", 30000, 15, 0),
+                signal("energy", "F32", 32106, 2, 0),
+                zero_gain,
+                signal("forcible_command", "ENUM", 47100, 1, 3),
+                signal("secret", "U16", 50000, 1, 7),
+            ],
+            scheme: Scheme { bat: vec![Bat { dtype: "U32".to_string(), addr: 41, len: 2, gain: 1, name: "total".to_string(), ..Default::default() }] },
+            ..Default::default()
+        };
+        assert_eq!(errors(&defs), vec![
+            "active_power (category 0) is I32 but has a len of 1 instead of 2",
+            "energy (category 0) has the unknown dtype F32",
+            "efficiency (category 0) has a gain of 0",
+            "secret has the unknown category 7",
+            "pack total extends past the 42 registers of a pack",
+        ]);
+    }
+
+    #[test]
+    fn finds_ignored_fields() {
+        let json: Value = serde_json::from_str(r#"{
+            "const": [{"dtype": "U16", "addr": 1, "len": 1, "gain": 1, "name": "a", "unit": "", "category": 0, "gruop": "fast", "writable": true, "min": 0, "max": 1, "interval": 5}],
+            "groups": {}, "pvGroup": "fast", "scehme": {},
+            "scheme": {"bat": [{"dtype": "ENUM", "addr": 0, "len": 1, "gain": 1, "name": "b", "unit": "", "labels": {}, "writable": true}]}
+        }"#).unwrap();
+        let mut findings = Vec::new();
+        lint_keys(Path::new("defs.json"), &json, &mut findings);
+        let messages: Vec<String> = findings.into_iter().map(|f| f.message).collect();
+        assert_eq!(messages, vec![
+            "defs.json: unknown key scehme",
+            "defs.json: \"a\" has the unknown field gruop",
+            "defs.json: pack \"b\" has the unknown field writable",
+        ]);
+    }
+}
@@ -1,6 +1,13 @@
 use serde_derive::Deserialize;
 use serde_derive::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
 
+/// Names for the codes of an `ENUM` or the bits of a `BITS` register
+pub type StateLabels = BTreeMap<u16, String>;
+
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, Clone)]
 pub enum PVSignalDataType {
     U16(u16),
@@ -9,7 +16,94 @@ pub enum PVSignalDataType {
     I32(i32),
     STR(String),
     UNK(u16),
+    /// Register holding one of a set of named codes
+    ENUM(u16, Arc<StateLabels>),
+    /// Register where every set bit has its own meaning
+    BITS(u16, Arc<StateLabels>),
+}
+impl fmt::Display for PVSignalDataType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PVSignalDataType::U16(x) => write!(f, "{}", x),
+            PVSignalDataType::I16(x) => write!(f, "{}", x),
+            PVSignalDataType::U32(x) => write!(f, "{}", x),
+            PVSignalDataType::I32(x) => write!(f, "{}", x),
+            PVSignalDataType::STR(x) => write!(f, "{}", x),
+            PVSignalDataType::UNK(x) => write!(f, "{:#06x}", x),
+            PVSignalDataType::ENUM(x, _) => write!(f, "{}", x),
+            PVSignalDataType::BITS(x, _) => write!(f, "{:#06x}", x),
+        }
+    }
+}
+
+impl PVSignalDataType {
+    /// Maps a dtype from the definitions to an empty value of that type,
+    /// `None` for dtypes we don't know
+    pub fn from_dtype(dtype: &str, labels: &StateLabels) -> Option<PVSignalDataType> {
+        match dtype {
+            "U16" => Some(PVSignalDataType::U16(0)),
+            "U32" => Some(PVSignalDataType::U32(0)),
+            "I16" => Some(PVSignalDataType::I16(0)),
+            "I32" => Some(PVSignalDataType::I32(0)),
+            "STR" => Some(PVSignalDataType::STR("".to_string())),
+            "ENUM" => Some(PVSignalDataType::ENUM(0, Arc::new(labels.clone()))),
+            "BITS" => Some(PVSignalDataType::BITS(0, Arc::new(labels.clone()))),
+            _ => None,
+        }
+    }
+
+    /// Numeric value as read from the registers, `None` for text and unknowns
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            PVSignalDataType::U16(x) => Some(*x as f64),
+            PVSignalDataType::I16(x) => Some(*x as f64),
+            PVSignalDataType::U32(x) => Some(*x as f64),
+            PVSignalDataType::I32(x) => Some(*x as f64),
+            PVSignalDataType::ENUM(x, _) | PVSignalDataType::BITS(x, _) => Some(*x as f64),
+            PVSignalDataType::STR(_) | PVSignalDataType::UNK(_) => None,
+        }
+    }
+
+    /// Names of the active bits of a `BITS` register, unnamed bits show up
+    /// as `bit N`
+    pub fn active_bits(&self) -> Vec<String> {
+        match self {
+            PVSignalDataType::BITS(x, labels) => (0..16u16)
+                .filter(|bit| x & (1 << bit) != 0)
+                .map(|bit| labels.get(&bit).cloned().unwrap_or_else(|| format!("bit {}", bit)))
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Value of the same type holding `raw`, `None` if it isn't a whole
+    /// number in the range of the type
+    pub fn with_raw(&self, raw: f64) -> Option<PVSignalDataType> {
+        let fits = |min: f64, max: f64| raw.fract() == 0.0 && raw >= min && raw <= max;
+        match self {
+            PVSignalDataType::U16(_) if fits(0.0, u16::MAX as f64) => Some(PVSignalDataType::U16(raw as u16)),
+            PVSignalDataType::I16(_) if fits(i16::MIN as f64, i16::MAX as f64) => Some(PVSignalDataType::I16(raw as i16)),
+            PVSignalDataType::U32(_) if fits(0.0, u32::MAX as f64) => Some(PVSignalDataType::U32(raw as u32)),
+            PVSignalDataType::I32(_) if fits(i32::MIN as f64, i32::MAX as f64) => Some(PVSignalDataType::I32(raw as i32)),
+            PVSignalDataType::ENUM(_, labels) if fits(0.0, u16::MAX as f64) => Some(PVSignalDataType::ENUM(raw as u16, labels.clone())),
+            PVSignalDataType::BITS(_, labels) if fits(0.0, u16::MAX as f64) => Some(PVSignalDataType::BITS(raw as u16, labels.clone())),
+            _ => None,
+        }
+    }
+
+    /// Text for `ENUM` and `BITS` registers, `None` for everything else
+    pub fn label(&self) -> Option<String> {
+        match self {
+            PVSignalDataType::ENUM(x, labels) => Some(labels.get(x).cloned().unwrap_or_else(|| format!("Unknown ({:#06x})", x))),
+            PVSignalDataType::BITS(..) => {
+                let bits = self.active_bits();
+                Some(if bits.is_empty() { "None".to_string() } else { bits.join(", ") })
+            }
+            _ => None,
+        }
+    }
 }
+
 #[derive(Debug, Clone)]
 pub struct PVSignal {
     pub data: PVSignalDataType,
@@ -21,6 +115,17 @@ pub struct PVSignal {
     pub time: i64,
 }
 
+impl PVSignal {
+    /// Engineering value, the raw value divided by the gain (e.g. 2305 with
+    /// a gain of 10 is 230.5 V). State codes and bitfields have none.
+    pub fn scaled(&self) -> Option<f64> {
+        match self.data {
+            PVSignalDataType::ENUM(..) | PVSignalDataType::BITS(..) => None,
+            _ => Some(self.data.as_f64()? / self.gain.max(1) as f64),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct PVString {
     pub voltage: PVSignal,
@@ -30,8 +135,21 @@ pub struct PVString {
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Root {
-    #[serde(rename = "const")]
+    /// Definitions these build on, relative to this file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub extends: Option<String>,
+    /// Signals of the extended definitions to leave out
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exclude: Vec<String>,
+    #[serde(rename = "const", default)]
     pub const_field: Vec<Const>,
+    /// Polling interval of each group in seconds, 0 to read only at startup
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub groups: BTreeMap<String, u64>,
+    /// Group of the PV string voltages and currents
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pv_group: Option<String>,
+    #[serde(default)]
     pub scheme: Scheme,
 }
 
@@ -45,6 +163,23 @@ pub struct Const {
     pub name: String,
     pub unit: String,
     pub category: u8,
+    /// Code or bit names for `ENUM` and `BITS` registers
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: StateLabels,
+    /// Control parameters that may be written
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub writable: bool,
+    /// Accepted range for writes, in engineering units (with the gain applied)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max: Option<f64>,
+    /// Polling group, see `Root::groups`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    /// Polling interval in seconds, overrides the group
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interval: Option<u64>,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -62,4 +197,10 @@ pub struct Bat {
     pub gain: u16,
     pub name: String,
     pub unit: String,
-}
\ No newline at end of file
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: StateLabels,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interval: Option<u64>,
+}
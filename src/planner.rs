@@ -0,0 +1,152 @@
+use crate::parser::types::*;
+use crate::registers::{RegisterMap, RegisterSlice};
+
+/// Maximum amount of registers a single Modbus read may return
+pub const MAX_BLOCK_SIZE: u16 = 125;
+/// Default amount of unused registers bridged to merge two blocks. The
+/// inverter answers reads of undefined registers with an exception, so
+/// nothing is bridged unless configured.
+pub const DEFAULT_MAX_GAP: u16 = 0;
+
+/// A contiguous range of registers fetched with a single request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReadBlock {
+    pub address: u16,
+    pub length: u16,
+    /// Indices of the signals covered by this block
+    pub signals: Vec<usize>,
+}
+
+/// Blocks to read and where each signal ends up once the blocks are read back to back
+#[derive(Debug, Clone, Default)]
+pub struct ReadPlan {
+    pub blocks: Vec<ReadBlock>,
+    pub map: RegisterMap,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ReadPlanner {
+    pub max_block_size: u16,
+    pub max_gap: u16,
+}
+
+impl Default for ReadPlanner {
+    fn default() -> Self {
+        ReadPlanner { max_block_size: MAX_BLOCK_SIZE, max_gap: DEFAULT_MAX_GAP }
+    }
+}
+
+impl ReadPlanner {
+    pub fn new(max_block_size: u16, max_gap: u16) -> ReadPlanner {
+        ReadPlanner { max_block_size: max_block_size.clamp(1, MAX_BLOCK_SIZE), max_gap }
+    }
+
+    /// Groups signals by address into as few blocks as the limits allow
+    pub fn plan(&self, signals: &[PVSignal]) -> ReadPlan {
+        let mut order: Vec<usize> = (0..signals.len()).collect();
+        order.sort_by_key(|&i| signals[i].address);
+
+        let mut blocks: Vec<ReadBlock> = Vec::new();
+        for i in order {
+            let s = &signals[i];
+            let start = s.address as u32;
+            let end = start + s.length as u32;
+            if let Some(block) = blocks.last_mut() {
+                let block_start = block.address as u32;
+                let block_end = block_start + block.length as u32;
+                let new_end = block_end.max(end);
+                if start <= block_end + self.max_gap as u32 && new_end - block_start <= self.max_block_size as u32 {
+                    block.length = (new_end - block_start) as u16;
+                    block.signals.push(i);
+                    continue;
+                }
+            }
+            blocks.push(ReadBlock { address: s.address, length: s.length, signals: vec![i] });
+        }
+
+        let mut slices = vec![RegisterSlice { offset: 0, length: 0 }; signals.len()];
+        let mut offset = 0;
+        for block in blocks.iter() {
+            for &i in block.signals.iter() {
+                slices[i] = RegisterSlice {
+                    offset: offset + (signals[i].address - block.address) as usize,
+                    length: signals[i].length as usize,
+                };
+            }
+            offset += block.length as usize;
+        }
+
+        ReadPlan { blocks, map: RegisterMap::from_slices(slices) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{gen_constdata, gen_pvdata, gen_storagedata, read_definitions, DEFAULT_DEFINITIONS};
+
+    fn signal(address: u16, length: u16) -> PVSignal {
+        PVSignal {
+            data: PVSignalDataType::U16(0),
+            address,
+            length,
+            name: format!("r{}", address),
+            unit: "".to_string(),
+            gain: 1,
+            time: 0,
+        }
+    }
+
+    fn defs() -> Root {
+        read_definitions(DEFAULT_DEFINITIONS).unwrap()
+    }
+
+    #[test]
+    fn merges_contiguous_and_bridges_gaps() {
+        let signals = vec![signal(100, 2), signal(102, 1), signal(105, 1), signal(200, 1)];
+        let plan = ReadPlanner::new(125, 2).plan(&signals);
+        assert_eq!(plan.blocks.len(), 2);
+        assert_eq!(plan.blocks[0], ReadBlock { address: 100, length: 6, signals: vec![0, 1, 2] });
+        assert_eq!(plan.map.slice(2), RegisterSlice { offset: 5, length: 1 });
+        assert_eq!(plan.map.slice(3), RegisterSlice { offset: 6, length: 1 });
+        assert_eq!(plan.map.total(), 7);
+    }
+
+    #[test]
+    fn respects_block_size() {
+        let signals: Vec<PVSignal> = (0..10).map(|i| signal(1000 + i * 2, 2)).collect();
+        let plan = ReadPlanner::new(6, 0).plan(&signals);
+        assert!(plan.blocks.iter().all(|b| b.length <= 6));
+        assert_eq!(plan.blocks.len(), 4);
+    }
+
+    #[test]
+    fn sorts_and_handles_overlaps() {
+        // the same register defined twice
+        let signals = vec![signal(37200, 1), signal(37113, 2), signal(37113, 2)];
+        let plan = ReadPlanner::new(125, 0).plan(&signals);
+        assert_eq!(plan.blocks.len(), 2);
+        assert_eq!(plan.map.slice(1), plan.map.slice(2));
+        assert_eq!(plan.map.slice(0), RegisterSlice { offset: 2, length: 1 });
+    }
+
+    #[test]
+    fn covers_every_definition() {
+        let mut signals = gen_constdata(&defs(), 0);
+        signals.append(&mut gen_constdata(&defs(), 1));
+        signals.append(&mut gen_storagedata(&defs(), &[0, 1, 2]));
+        signals.extend(gen_pvdata(4).into_iter().flat_map(|x| [x.voltage, x.current]));
+        let plan = ReadPlanner::default().plan(&signals);
+        let mut seen = vec![false; signals.len()];
+        for b in plan.blocks.iter() {
+            assert!(b.length <= MAX_BLOCK_SIZE);
+            for &i in b.signals.iter() {
+                assert!(signals[i].address >= b.address);
+                assert!(signals[i].address + signals[i].length <= b.address + b.length);
+                seen[i] = true;
+            }
+        }
+        assert!(seen.iter().all(|x| *x));
+        assert!(plan.blocks.len() < signals.len());
+    }
+}
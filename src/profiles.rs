@@ -0,0 +1,169 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use crate::parser::{read_definitions, types::*};
+
+/// The smart power sensor, registers that only answer with a meter connected
+const METER_ADDRESSES: Range<u16> = 37100..37200;
+/// Status of the meter, 1 when it is connected and working
+pub const METER_STATUS_ADDRESS: u16 = 37100;
+
+/// Inverter series, the last part of the model name (`SUN2000-8KTL-M1`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Series {
+    /// Three phase, without battery support
+    M0,
+    /// Three phase hybrid
+    M1,
+    M2,
+    /// Single phase hybrid
+    L1,
+}
+
+impl Series {
+    pub const ALL: [Series; 4] = [Series::M0, Series::M1, Series::M2, Series::L1];
+
+    /// Series of a model identification, `None` for models we don't know
+    pub fn detect(model: &str) -> Option<Series> {
+        model.trim().split('-').skip(1).find_map(|part| Series::ALL.into_iter().find(|s| part.eq_ignore_ascii_case(&s.to_string())))
+    }
+
+    pub fn supports_battery(&self) -> bool {
+        *self != Series::M0
+    }
+
+    /// Definitions of the series next to the base ones, `definitions.json`
+    /// becomes `definitions.l1.json`
+    pub fn path(&self, base: &str) -> PathBuf {
+        Path::new(base).with_extension(format!("{}.json", self.to_string().to_lowercase()))
+    }
+}
+
+impl fmt::Display for Series {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+/// Definitions of the series that have a file next to the base definitions
+pub fn series_files(base: &str) -> Vec<(Series, String)> {
+    Series::ALL.into_iter().map(|s| (s, s.path(base))).filter(|(_, p)| p.exists()).map(|(s, p)| (s, p.to_string_lossy().into_owned())).collect()
+}
+
+/// What was found on the device, decides which signals get polled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Profile {
+    /// `None` for unknown models, which get the base definitions
+    pub series: Option<Series>,
+    pub battery: bool,
+    pub meter: bool,
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let with = |present: bool| if present { "with" } else { "without" };
+        match self.series {
+            Some(s) => write!(f, "{}", s)?,
+            None => write!(f, "unknown series")?,
+        }
+        write!(f, " {} battery, {} meter", with(self.battery), with(self.meter))
+    }
+}
+
+/// Definitions of every series, each extending the base definitions
+#[derive(Debug, Clone)]
+pub struct Registry {
+    base: Root,
+    series: BTreeMap<Series, Root>,
+}
+
+impl Registry {
+    /// Every series gets `base`
+    pub fn new(base: Root) -> Registry {
+        Registry { base, series: BTreeMap::new() }
+    }
+
+    /// Loads the base definitions from `path` and those of every series
+    /// that has a file next to it
+    pub fn load(path: &str) -> io::Result<Registry> {
+        let mut registry = Registry::new(read_definitions(path)?);
+        for (s, path) in series_files(path) {
+            let defs = read_definitions(&path).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)))?;
+            registry.series.insert(s, defs);
+        }
+        Ok(registry)
+    }
+
+    pub fn base(&self) -> &Root {
+        &self.base
+    }
+
+    /// Every set of definitions, the base one first, named by series
+    pub fn all(&self) -> Vec<(String, &Root)> {
+        let mut all = vec![("base".to_string(), &self.base)];
+        all.extend(self.series.iter().map(|(s, defs)| (s.to_string(), defs)));
+        all
+    }
+
+    /// Definitions for a device, without the battery and meter signals if it has none.
+    /// The meter status stays to notice a meter that comes back.
+    pub fn definitions(&self, profile: &Profile) -> Root {
+        let mut defs = profile.series.and_then(|s| self.series.get(&s)).unwrap_or(&self.base).clone();
+        if !profile.battery {
+            defs.const_field.retain(|c| c.category != 2 && c.category != 3);
+            defs.scheme.bat.clear();
+        }
+        if !profile.meter {
+            defs.const_field.retain(|c| c.addr == METER_STATUS_ADDRESS || !METER_ADDRESSES.contains(&c.addr));
+        }
+        defs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::DEFAULT_DEFINITIONS;
+
+    #[test]
+    fn detects_the_series() {
+        assert_eq!(Series::detect("SUN2000-8KTL-M1"), Some(Series::M1));
+        assert_eq!(Series::detect("SUN2000-5KTL-L1 "), Some(Series::L1));
+        assert_eq!(Series::detect("SUN2000-20KTL-m2"), Some(Series::M2));
+        assert_eq!(Series::detect("SUN2000-100KTL-M0"), Some(Series::M0));
+        assert_eq!(Series::detect("M1"), None);
+        assert_eq!(Series::detect("SUN2000-60KTL-HV-D1"), None);
+        assert_eq!(Series::L1.path("./defs/definitions.json"), PathBuf::from("./defs/definitions.l1.json"));
+    }
+
+    #[test]
+    fn single_phase_inherits_the_base() {
+        let registry = Registry::load(DEFAULT_DEFINITIONS).unwrap();
+        let full = Profile { series: Some(Series::L1), battery: true, meter: true };
+        let l1 = registry.definitions(&full);
+        let base = registry.base();
+        assert!(!l1.const_field.iter().any(|c| c.name == "c_volt"));
+        assert!(l1.const_field.iter().any(|c| c.name == "a_volt"));
+        assert_eq!(l1.groups, base.groups);
+        assert_eq!(l1.scheme, base.scheme);
+        assert_eq!(registry.definitions(&Profile { series: Some(Series::M2), ..full }), *base);
+        assert_eq!(registry.definitions(&Profile { series: None, ..full }), *base);
+        assert_eq!(registry.all().len(), 2);
+    }
+
+    #[test]
+    fn leaves_out_missing_hardware() {
+        let registry = Registry::load(DEFAULT_DEFINITIONS).unwrap();
+        let defs = registry.definitions(&Profile { series: Some(Series::M1), battery: false, meter: false });
+        assert!(defs.const_field.iter().all(|c| c.category < 2));
+        assert!(defs.scheme.bat.is_empty());
+        assert!(!defs.const_field.iter().any(|c| c.name == "meter_active_power"));
+        assert!(defs.const_field.iter().any(|c| c.name == "meter_status"));
+        assert!(defs.const_field.iter().any(|c| c.name == "active_power"));
+        let defs = registry.definitions(&Profile { series: Some(Series::M1), battery: true, meter: false });
+        assert!(defs.const_field.iter().any(|c| c.name == "forcible_command"));
+    }
+}
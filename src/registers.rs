@@ -0,0 +1,235 @@
+use crate::parser::types::*;
+
+/// Position of a single signal inside a concatenated register buffer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegisterSlice {
+    pub offset: usize,
+    pub length: usize,
+}
+
+/// Tracks where each signal of a list lives in the buffer that results from
+/// reading its registers, so multi-register values don't shift every
+/// following signal.
+#[derive(Debug, Clone, Default)]
+pub struct RegisterMap {
+    slices: Vec<RegisterSlice>,
+    total: usize,
+}
+
+impl RegisterMap {
+    /// Builds a map from precomputed slices, see `ReadPlanner::plan`
+    pub fn from_slices(slices: Vec<RegisterSlice>) -> RegisterMap {
+        let total = slices.iter().map(|s| s.offset + s.length).max().unwrap_or(0);
+        RegisterMap { slices, total }
+    }
+
+    pub fn slice(&self, index: usize) -> RegisterSlice {
+        self.slices[index]
+    }
+
+    /// Total amount of registers covered by the map
+    pub fn total(&self) -> usize {
+        self.total
+    }
+
+    /// Decodes `data` into the signals the map was built from
+    pub fn decode(&self, signals: &mut [PVSignal], data: &[u16]) {
+        if data.len() < self.total() {
+            error!("Register buffer too short: got {} expected {}", data.len(), self.total());
+        }
+        for (i, signal) in signals.iter_mut().enumerate().take(self.slices.len()) {
+            let slice = self.slice(i);
+            match data.get(slice.offset..slice.offset + slice.length) {
+                Some(words) => signal.data = decode_value(&signal.data, words),
+                None => warn!("No registers for: {}", signal.name),
+            }
+        }
+    }
+}
+
+/// Decodes raw register words into the type given by `template`
+pub fn decode_value(template: &PVSignalDataType, words: &[u16]) -> PVSignalDataType {
+    match template {
+        PVSignalDataType::U16(_) => PVSignalDataType::U16(words[0]),
+        PVSignalDataType::I16(_) => PVSignalDataType::I16(words[0] as i16),
+        PVSignalDataType::U32(_) => PVSignalDataType::U32(decode_u32(words)),
+        PVSignalDataType::I32(_) => PVSignalDataType::I32(decode_u32(words) as i32),
+        PVSignalDataType::STR(_) => PVSignalDataType::STR(decode_string(words)),
+        // unknown registers are mostly bitfields, keep the raw word around
+        PVSignalDataType::UNK(_) => PVSignalDataType::UNK(words[0]),
+        PVSignalDataType::ENUM(_, labels) => PVSignalDataType::ENUM(words[0], labels.clone()),
+        PVSignalDataType::BITS(_, labels) => PVSignalDataType::BITS(words[0], labels.clone()),
+    }
+}
+
+/// Register words for a numeric value, the inverse of `decode_value`.
+/// Text and unknowns can't be written.
+pub fn encode_value(value: &PVSignalDataType) -> Option<Vec<u16>> {
+    match value {
+        PVSignalDataType::U16(x) => Some(vec![*x]),
+        PVSignalDataType::I16(x) => Some(vec![*x as u16]),
+        PVSignalDataType::U32(x) => Some(vec![(x >> 16) as u16, *x as u16]),
+        PVSignalDataType::I32(x) => Some(vec![(*x as u32 >> 16) as u16, *x as u16]),
+        PVSignalDataType::ENUM(x, _) | PVSignalDataType::BITS(x, _) => Some(vec![*x]),
+        PVSignalDataType::STR(_) | PVSignalDataType::UNK(_) => None,
+    }
+}
+
+/// Big-endian 32 bit value spread over two registers
+pub fn decode_u32(words: &[u16]) -> u32 {
+    (words[0] as u32) << 16 | words[1] as u32
+}
+
+/// Each register contains 2 chars, trailing NULs are padding
+pub fn decode_string(words: &[u16]) -> String {
+    let mut text = String::new();
+    for w in words {
+        text.push((w >> 8) as u8 as char);
+        text.push((w & 0xFF) as u8 as char);
+    }
+    text.trim_end_matches('\0').to_string()
+}
+
+/// `len` registers holding `text`, cut off or padded with NULs
+pub fn encode_string(text: &str, len: u16) -> Vec<u16> {
+    let bytes = text.as_bytes();
+    (0..len as usize).map(|i| {
+        let hi = *bytes.get(i * 2).unwrap_or(&0) as u16;
+        let lo = *bytes.get(i * 2 + 1).unwrap_or(&0) as u16;
+        hi << 8 | lo
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{gen_constdata, gen_storagedata, read_definitions, DEFAULT_DEFINITIONS};
+
+    /// Layout of signals read one after another without gaps
+    fn concatenated(signals: &[PVSignal]) -> RegisterMap {
+        let mut slices = Vec::new();
+        let mut offset = 0;
+        for s in signals {
+            slices.push(RegisterSlice { offset, length: s.length as usize });
+            offset += s.length as usize;
+        }
+        RegisterMap::from_slices(slices)
+    }
+
+    fn encode(signal: &PVSignal, words: &mut [u16]) {
+        match &signal.data {
+            PVSignalDataType::U16(v) => words[0] = *v,
+            PVSignalDataType::I16(v) => words[0] = *v as u16,
+            PVSignalDataType::U32(v) => {
+                words[0] = (v >> 16) as u16;
+                words[1] = *v as u16;
+            }
+            PVSignalDataType::I32(v) => {
+                words[0] = (*v as u32 >> 16) as u16;
+                words[1] = *v as u16;
+            }
+            PVSignalDataType::STR(s) => words.copy_from_slice(&encode_string(s, words.len() as u16)),
+            PVSignalDataType::UNK(v) | PVSignalDataType::ENUM(v, _) | PVSignalDataType::BITS(v, _) => words[0] = *v,
+        }
+    }
+
+    /// Fills every signal with a value derived from its index
+    fn sample_values(signals: &[PVSignal]) -> Vec<PVSignal> {
+        let mut out = signals.to_vec();
+        for (i, s) in out.iter_mut().enumerate() {
+            let i = i as i32 + 1;
+            s.data = match &s.data {
+                PVSignalDataType::U16(_) => PVSignalDataType::U16(i as u16 * 3),
+                PVSignalDataType::I16(_) => PVSignalDataType::I16(-(i as i16) * 7),
+                PVSignalDataType::U32(_) => PVSignalDataType::U32(0x0001_0000 * i as u32 + 5),
+                PVSignalDataType::I32(_) => PVSignalDataType::I32(-100_000 * i),
+                PVSignalDataType::STR(_) => PVSignalDataType::STR(format!("SUN2000-{}", i)),
+                PVSignalDataType::UNK(_) => PVSignalDataType::UNK(0b1010_0000_0000_0001),
+                PVSignalDataType::ENUM(_, labels) => PVSignalDataType::ENUM(0x0200, labels.clone()),
+                PVSignalDataType::BITS(_, labels) => PVSignalDataType::BITS(0b1000_0000_1000_0010, labels.clone()),
+            };
+        }
+        out
+    }
+
+    fn build_buffer(map: &RegisterMap, signals: &[PVSignal]) -> Vec<u16> {
+        let mut buf = vec![0u16; map.total()];
+        for (i, s) in signals.iter().enumerate() {
+            let slice = map.slice(i);
+            encode(s, &mut buf[slice.offset..slice.offset + slice.length]);
+        }
+        buf
+    }
+
+    fn assert_roundtrip(template: Vec<PVSignal>) {
+        let expected = sample_values(&template);
+        let map = concatenated(&template);
+        let buf = build_buffer(&map, &expected);
+        let mut decoded = template.clone();
+        map.decode(&mut decoded, &buf);
+        for (d, e) in decoded.iter().zip(expected.iter()) {
+            assert_eq!(format!("{:?}", d.data), format!("{:?}", e.data), "signal {}", d.name);
+        }
+    }
+
+    fn defs() -> Root {
+        read_definitions(DEFAULT_DEFINITIONS).unwrap()
+    }
+
+    #[test]
+    fn offsets_follow_lengths() {
+        let signals = gen_constdata(&defs(), 0);
+        let map = concatenated(&signals);
+        let mut expected = 0;
+        for (i, s) in signals.iter().enumerate() {
+            assert_eq!(map.slice(i), RegisterSlice { offset: expected, length: s.length as usize });
+            expected += s.length as usize;
+        }
+        assert_eq!(map.total(), expected);
+    }
+
+    #[test]
+    fn decodes_general_data() {
+        assert_roundtrip(gen_constdata(&defs(), 0));
+    }
+
+    #[test]
+    fn decodes_pgs_data() {
+        assert_roundtrip(gen_constdata(&defs(), 1));
+    }
+
+    #[test]
+    fn decodes_storage_data() {
+        assert_roundtrip(gen_storagedata(&defs(), &[0, 1, 2]));
+    }
+
+    #[test]
+    fn decodes_after_multi_register_signal() {
+        // model_ident takes 15 registers, num_strings must come right after it
+        let mut signals = gen_constdata(&defs(), 0);
+        let map = concatenated(&signals);
+        let mut buf = vec![0u16; map.total()];
+        buf[15] = 4;
+        map.decode(&mut signals, &buf);
+        let num_strings = signals.iter().find(|s| s.name == "num_strings").unwrap();
+        assert!(matches!(num_strings.data, PVSignalDataType::U16(4)));
+    }
+
+    #[test]
+    fn encodes_what_it_decodes() {
+        for value in [PVSignalDataType::U16(1000), PVSignalDataType::I16(-5), PVSignalDataType::U32(70000), PVSignalDataType::I32(-1500)] {
+            let words = encode_value(&value).unwrap();
+            assert_eq!(format!("{:?}", decode_value(&value, &words)), format!("{:?}", value));
+        }
+        assert!(encode_value(&PVSignalDataType::STR("SUN".to_string())).is_none());
+    }
+
+    #[test]
+    fn decodes_signed_and_strings() {
+        assert!(matches!(decode_value(&PVSignalDataType::I32(0), &[0xFFFF, 0xFFFE]), PVSignalDataType::I32(-2)));
+        assert!(matches!(decode_value(&PVSignalDataType::I16(0), &[0x8000]), PVSignalDataType::I16(i16::MIN)));
+        assert_eq!(decode_string(&[0x5355, 0x4E00, 0x0000]), "SUN");
+        assert_eq!(encode_string("SUN", 3), vec![0x5355, 0x4E00, 0x0000]);
+        assert_eq!(encode_string("SUN2000", 2), vec![0x5355, 0x4E32]);
+    }
+}
@@ -0,0 +1,170 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::parser::types::*;
+use crate::sinks::Category;
+
+/// How often a signal is read
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interval {
+    /// Read at startup only, for values that never change
+    Once,
+    Every(Duration),
+}
+
+impl Interval {
+    fn from_secs(secs: u64) -> Interval {
+        match secs {
+            0 => Interval::Once,
+            s => Interval::Every(Duration::from_secs(s)),
+        }
+    }
+
+    /// Signals this close to being due are read along with the due ones, so
+    /// signals of one interval stay in the same requests
+    fn slack(&self) -> i64 {
+        match self {
+            Interval::Once => 0,
+            Interval::Every(d) => (d.as_millis() as i64 / 2).min(1000),
+        }
+    }
+}
+
+/// Polling intervals of every signal from the `groups` and `interval`
+/// fields of the definitions, signals without either use the default
+#[derive(Debug, Clone)]
+pub struct Schedule {
+    default: Duration,
+    consts: HashMap<(Category, String), Interval>,
+    bat: HashMap<String, Interval>,
+    pv: Option<Interval>,
+}
+
+/// Category the signals of a `Const` end up in, control registers are never polled
+fn const_category(category: u8) -> Option<Category> {
+    match category {
+        0 => Some(Category::General),
+        1 => Some(Category::Pgs),
+        2 => Some(Category::Storage),
+        _ => None,
+    }
+}
+
+impl Schedule {
+    pub fn new(defs: &Root, default: Duration) -> Result<Schedule, String> {
+        let resolve = |name: &str, group: &Option<String>, interval: Option<u64>| -> Result<Option<Interval>, String> {
+            if let Some(secs) = interval {
+                return Ok(Some(Interval::from_secs(secs)));
+            }
+            match group {
+                Some(g) => match defs.groups.get(g) {
+                    Some(&secs) => Ok(Some(Interval::from_secs(secs))),
+                    None => Err(format!("{} is in the unknown group {}", name, g)),
+                },
+                None => Ok(None),
+            }
+        };
+        let mut consts = HashMap::new();
+        for c in defs.const_field.iter() {
+            if let (Some(category), Some(interval)) = (const_category(c.category), resolve(&c.name, &c.group, c.interval)?) {
+                consts.insert((category, c.name.clone()), interval);
+            }
+        }
+        let mut bat = HashMap::new();
+        for b in defs.scheme.bat.iter() {
+            if let Some(interval) = resolve(&b.name, &b.group, b.interval)? {
+                bat.insert(b.name.clone(), interval);
+            }
+        }
+        let pv = resolve("pvGroup", &defs.pv_group, None)?;
+        Ok(Schedule { default, consts, bat, pv })
+    }
+
+    /// Schedule of other definitions with the same default interval
+    pub fn with_definitions(&self, defs: &Root) -> Result<Schedule, String> {
+        Schedule::new(defs, self.default)
+    }
+
+    pub fn interval(&self, category: Category, name: &str) -> Interval {
+        let interval = match category {
+            Category::Pv => self.pv,
+            // pack values are named after the scheme, e.g. `pack1_soc`
+            Category::Storage => self.consts.get(&(category, name.to_string())).copied().or_else(|| {
+                let (_, field) = name.strip_prefix("pack")?.split_once('_')?;
+                self.bat.get(field).copied()
+            }),
+            _ => self.consts.get(&(category, name.to_string())).copied(),
+        };
+        interval.unwrap_or(Interval::Every(self.default))
+    }
+
+    /// Whether `signal` has to be read at `now` (unix millis), signals that
+    /// were never read successfully always are
+    pub fn is_due(&self, category: Category, signal: &PVSignal, now: i64) -> bool {
+        match self.next_read(category, signal) {
+            Some(at) => at <= now + self.interval(category, &signal.name).slack(),
+            None => false,
+        }
+    }
+
+    /// Unix millis at which `signal` is due, `None` if it never will be again
+    pub fn next_read(&self, category: Category, signal: &PVSignal) -> Option<i64> {
+        if signal.time == 0 {
+            return Some(0);
+        }
+        match self.interval(category, &signal.name) {
+            Interval::Once => None,
+            Interval::Every(d) => Some(signal.time + d.as_millis() as i64),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{gen_constdata, read_definitions, DEFAULT_DEFINITIONS};
+
+    fn schedule() -> Schedule {
+        Schedule::new(&read_definitions(DEFAULT_DEFINITIONS).unwrap(), Duration::from_secs(90)).unwrap()
+    }
+
+    #[test]
+    fn intervals_from_groups() {
+        let s = schedule();
+        assert_eq!(s.interval(Category::General, "model_ident"), Interval::Once);
+        assert_eq!(s.interval(Category::General, "active_power"), Interval::Every(Duration::from_secs(5)));
+        assert_eq!(s.interval(Category::General, "acc_energy_yield"), Interval::Every(Duration::from_secs(300)));
+        assert_eq!(s.interval(Category::General, "status"), Interval::Every(Duration::from_secs(90)));
+        // same name, different category
+        assert_eq!(s.interval(Category::Storage, "sn"), Interval::Once);
+        assert_eq!(s.interval(Category::Storage, "pack4_charge_discharge_power"), Interval::Every(Duration::from_secs(5)));
+        assert_eq!(s.interval(Category::Storage, "pack0_volt"), Interval::Every(Duration::from_secs(90)));
+        assert_eq!(s.interval(Category::Pv, "pv_0_voltage"), Interval::Every(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn signals_become_due() {
+        let s = schedule();
+        let mut signals = gen_constdata(&read_definitions(DEFAULT_DEFINITIONS).unwrap(), 0);
+        let mut model = signals.remove(0);
+        let mut power = signals.into_iter().find(|x| x.name == "active_power").unwrap();
+        assert!(s.is_due(Category::General, &model, 1000));
+        model.time = 1000;
+        power.time = 1000;
+        assert!(!s.is_due(Category::General, &model, 1_000_000_000));
+        assert_eq!(s.next_read(Category::General, &model), None);
+        assert!(!s.is_due(Category::General, &power, 2000));
+        // read along with signals that are due a moment earlier
+        assert!(s.is_due(Category::General, &power, 5500));
+        assert_eq!(s.next_read(Category::General, &power), Some(6000));
+    }
+
+    #[test]
+    fn rejects_unknown_groups() {
+        let mut defs = read_definitions(DEFAULT_DEFINITIONS).unwrap();
+        defs.const_field[0].group = Some("sometimes".to_string());
+        assert!(Schedule::new(&defs, Duration::from_secs(90)).is_err());
+        defs.const_field[0].interval = Some(10);
+        assert!(Schedule::new(&defs, Duration::from_secs(90)).is_ok());
+    }
+}
@@ -0,0 +1,470 @@
+use std::collections::BTreeMap;
+use std::f64::consts::PI;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::{Local, NaiveTime, Timelike};
+use clap::Args;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time;
+
+use crate::parser::{gen_batdata, gen_pvdata, gen_signal, pack_address, types::*, PACKS_PER_UNIT, UNIT_SN_ADDRESSES};
+use crate::registers::{encode_string, encode_value};
+
+/// Modbus exception codes sent by the simulator
+const ILLEGAL_FUNCTION: u8 = 0x01;
+const ILLEGAL_DATA_ADDRESS: u8 = 0x02;
+const ILLEGAL_DATA_VALUE: u8 = 0x03;
+const GATEWAY_TARGET_FAILED: u8 = 0x0B;
+
+/// Values of a freshly installed SUN2000-8KTL-M1, in engineering units
+const DEFAULTS: &[(&str, &str)] = &[
+    ("model_ident", "SUN2000-8KTL-M1"),
+    ("rated_power", "8"),
+    ("max_active_power", "8.8"),
+    ("max_apparent_power", "8.8"),
+    ("max_reactive_feed_power", "5.28"),
+    ("max_reactive_absorb_power", "-5.28"),
+    ("a_volt", "230"),
+    ("b_volt", "230"),
+    ("c_volt", "230"),
+    ("grid_freq", "50"),
+    ("efficiency", "98.2"),
+    ("temp", "35"),
+    ("insul_resist", "3"),
+    ("power_factor", "1"),
+    ("acc_energy_yield", "12345.67"),
+    ("active_power_derating", "100"),
+    ("meter_status", "1"),
+];
+
+/// Battery pack fields by scheme name
+const PACK_DEFAULTS: &[(&str, &str)] = &[
+    ("firmware", "V100R002C00"),
+    ("status", "2"),
+    ("soc", "55"),
+    ("volt", "450"),
+];
+
+/// Status codes, see the labels of `status` in definitions.json
+const ON_GRID: f64 = 512.0;
+const NO_IRRADIATION: f64 = 40960.0;
+
+/// Clear sky day, sunrise and sunset in seconds after midnight
+const SUNRISE: f64 = 6.0 * 3600.0;
+const SUNSET: f64 = 20.0 * 3600.0;
+/// DC to AC conversion
+const EFFICIENCY: f64 = 0.97;
+
+/// What the simulated inverter looks like
+#[derive(Debug, Clone, PartialEq, Args)]
+pub struct SimulatorConfig {
+    /// Number of PV strings
+    #[arg(long, default_value_t = 2)]
+    pub pv_strings: u8,
+    /// Installed battery packs, 0-2 on the first unit, 3-5 on the second
+    #[arg(long, value_delimiter = ',')]
+    pub packs: Vec<u8>,
+    /// DC power at noon in watts
+    #[arg(long, default_value_t = 8000.0)]
+    pub peak_power_w: f64,
+    /// Fixed value for a signal as `name=value`, numbers in engineering
+    /// units. Signals that follow the sun stop doing so.
+    #[arg(long = "set", value_parser = parse_value)]
+    pub values: Vec<(String, String)>,
+    /// Register answered with an "illegal data address" exception
+    #[arg(long = "exception")]
+    pub exceptions: Vec<u16>,
+    /// Delay before every response
+    #[arg(long, default_value_t = 0)]
+    pub latency_ms: u64,
+    /// Answer requests touching registers no signal is defined at with an
+    /// "illegal data address" exception instead of zeros, like the real inverter
+    #[arg(long)]
+    pub reject_undefined: bool,
+    /// Time of day to simulate (e.g. 13:00:00) instead of the local time
+    #[arg(long)]
+    pub time: Option<NaiveTime>,
+}
+
+impl Default for SimulatorConfig {
+    fn default() -> Self {
+        SimulatorConfig {
+            pv_strings: 2,
+            packs: Vec::new(),
+            peak_power_w: 8000.0,
+            values: Vec::new(),
+            exceptions: Vec::new(),
+            latency_ms: 0,
+            reject_undefined: false,
+            time: None,
+        }
+    }
+}
+
+fn parse_value(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((name, value)) => Ok((name.to_string(), value.to_string())),
+        None => Err(format!("expected name=value, got {}", s)),
+    }
+}
+
+/// Share of the peak power the sun provides at `seconds` after midnight
+fn daylight(seconds: f64) -> f64 {
+    if seconds <= SUNRISE || seconds >= SUNSET {
+        return 0.0;
+    }
+    (PI * (seconds - SUNRISE) / (SUNSET - SUNRISE)).sin()
+}
+
+/// Energy in kWh the curve delivered from sunrise until `seconds`
+fn energy_today(peak_power_w: f64, seconds: f64) -> f64 {
+    let day = SUNSET - SUNRISE;
+    let x = ((seconds - SUNRISE) / day).clamp(0.0, 1.0);
+    peak_power_w * day / PI * (1.0 - (PI * x).cos()) / 3600.0 / 1000.0
+}
+
+/// Register words holding the engineering `value`, codes of `ENUM` and
+/// `BITS` registers are taken as they are
+fn encode_number(signal: &PVSignal, value: f64) -> Option<Vec<u16>> {
+    let raw = match signal.data {
+        PVSignalDataType::ENUM(..) | PVSignalDataType::BITS(..) => value,
+        _ => (value * signal.gain.max(1) as f64).round(),
+    };
+    encode_value(&signal.data.with_raw(raw)?)
+}
+
+fn encode(signal: &PVSignal, value: &str) -> Result<Vec<u16>, String> {
+    if let PVSignalDataType::STR(_) = signal.data {
+        return Ok(encode_string(value, signal.length));
+    }
+    value.parse().ok()
+        .and_then(|v| encode_number(signal, v))
+        .ok_or_else(|| format!("{} is not a valid value for {}", value, signal.name))
+}
+
+/// Register image of the inverter
+#[derive(Debug, Default)]
+struct Image {
+    registers: BTreeMap<u16, u16>,
+}
+
+impl Image {
+    /// Words that would land past the last register are dropped
+    fn set(&mut self, address: u16, words: &[u16]) {
+        for (a, w) in (address as u32..0x10000).zip(words) {
+            self.registers.insert(a as u16, *w);
+        }
+    }
+
+    /// Registers nothing is defined at read as zero
+    fn is_defined(&self, address: u16, count: u16) -> bool {
+        (address as u32..address as u32 + count as u32).all(|a| self.registers.contains_key(&(a as u16)))
+    }
+
+    fn get(&self, address: u16, count: u16) -> Vec<u16> {
+        (address as u32..address as u32 + count as u32).map(|a| self.registers.get(&(a as u16)).copied().unwrap_or(0)).collect()
+    }
+}
+
+/// A SUN2000 answering Modbus TCP requests from a register image generated
+/// from the definitions. PV values follow the sun of a clear day.
+#[derive(Debug, Clone)]
+pub struct Simulator {
+    unit_id: u8,
+    config: SimulatorConfig,
+    image: Arc<Mutex<Image>>,
+    /// Signals computed from the time of day
+    sunlit: Vec<PVSignal>,
+}
+
+impl Simulator {
+    pub fn new(defs: &Root, unit_id: u8, config: SimulatorConfig) -> Result<Simulator, String> {
+        if let Some(&ident) = config.packs.iter().find(|&&p| p >= PACKS_PER_UNIT * UNIT_SN_ADDRESSES.len() as u8) {
+            return Err(format!("there is no battery pack {}", ident));
+        }
+        let mut signals: Vec<PVSignal> = defs.const_field.iter().filter_map(gen_signal).collect();
+        let mut values: Vec<(String, String)> = DEFAULTS.iter().map(|(n, v)| (n.to_string(), v.to_string())).collect();
+        values.push(("num_strings".to_string(), config.pv_strings.to_string()));
+        values.push(("num_trackers".to_string(), config.pv_strings.to_string()));
+
+        let mut image = Image::default();
+        for (unit, &address) in UNIT_SN_ADDRESSES.iter().enumerate() {
+            let unit = unit as u8;
+            if config.packs.iter().any(|p| p / PACKS_PER_UNIT == unit) {
+                image.set(address, &encode_string(&format!("SIMBAT{:04}", unit + 1), 10));
+                // the rated powers of the storage are those of the first unit
+                if unit == 0 {
+                    values.push(("rated_charge_power".to_string(), "5000".to_string()));
+                    values.push(("rated_discharge_power".to_string(), "5000".to_string()));
+                }
+            }
+        }
+        let mut packs = config.packs.clone();
+        packs.sort();
+        packs.dedup();
+        for ident in packs {
+            values.push((format!("pack{}_sn", ident), format!("SIMPACK{:04}", ident)));
+            for (name, value) in PACK_DEFAULTS {
+                values.push((format!("pack{}_{}", ident, name), value.to_string()));
+            }
+            signals.append(&mut gen_batdata(defs, pack_address(ident), ident));
+        }
+        for pv in gen_pvdata(config.pv_strings) {
+            signals.push(pv.voltage);
+            signals.push(pv.current);
+        }
+
+        for s in signals.iter() {
+            for a in s.address as u32..(s.address as u32 + s.length as u32).min(0x10000) {
+                image.registers.entry(a as u16).or_insert(0);
+            }
+        }
+        for (name, value) in values.iter() {
+            for s in signals.iter().filter(|s| s.name == *name) {
+                image.set(s.address, &encode(s, value)?);
+            }
+        }
+        // configured values last, they take precedence over everything else
+        for (name, value) in config.values.iter() {
+            let matching: Vec<&PVSignal> = signals.iter().filter(|s| s.name == *name).collect();
+            if matching.is_empty() {
+                return Err(format!("no signal named {}", name));
+            }
+            for s in matching {
+                image.set(s.address, &encode(s, value)?);
+            }
+        }
+
+        let follows_the_sun = |s: &PVSignal| {
+            let general = ["input_power", "active_power", "day_energy_yield"].contains(&s.name.as_str()) || (s.name == "status" && s.address == 32089);
+            (general || s.name.starts_with("pv_")) && !config.values.iter().any(|(name, _)| *name == s.name)
+        };
+        let sunlit = signals.into_iter().filter(follows_the_sun).collect();
+        Ok(Simulator { unit_id, config, image: Arc::new(Mutex::new(image)), sunlit })
+    }
+
+    /// Writes the values that follow the sun for `seconds` after midnight
+    fn update(&self, image: &mut Image, seconds: f64) {
+        let sun = daylight(seconds);
+        let dc_w = self.config.peak_power_w * sun;
+        let strings = self.config.pv_strings.max(1) as f64;
+        let voltage = if sun > 0.0 { 330.0 + 60.0 * sun } else { 0.0 };
+        for s in self.sunlit.iter() {
+            let value = match s.name.as_str() {
+                "input_power" => dc_w / 1000.0,
+                "active_power" => dc_w * EFFICIENCY / 1000.0,
+                "day_energy_yield" => energy_today(self.config.peak_power_w, seconds) * EFFICIENCY,
+                "status" if sun > 0.0 => ON_GRID,
+                "status" => NO_IRRADIATION,
+                name if name.ends_with("_voltage") => voltage,
+                _ if voltage > 0.0 => dc_w / strings / voltage,
+                _ => 0.0,
+            };
+            if let Some(words) = encode_number(s, value) {
+                image.set(s.address, &words);
+            }
+        }
+    }
+
+    fn seconds_of_day(&self) -> f64 {
+        let time = self.config.time.unwrap_or_else(|| Local::now().time());
+        time.num_seconds_from_midnight() as f64
+    }
+
+    fn is_exception(&self, image: &Image, address: u16, count: u16) -> bool {
+        (self.config.reject_undefined && !image.is_defined(address, count))
+            || self.config.exceptions.iter().any(|&e| (address as u32..address as u32 + count as u32).contains(&(e as u32)))
+    }
+
+    /// Response PDU for a request PDU sent to `unit`
+    fn respond(&self, unit: u8, pdu: &[u8]) -> Vec<u8> {
+        let function = pdu.first().copied().unwrap_or(0);
+        let exception = |code: u8| vec![function | 0x80, code];
+        if unit != self.unit_id {
+            return exception(GATEWAY_TARGET_FAILED);
+        }
+        let word = |i: usize| pdu.get(i..i + 2).map(|b| u16::from_be_bytes([b[0], b[1]]));
+        let (Some(address), Some(value)) = (word(1), word(3)) else {
+            return exception(ILLEGAL_DATA_VALUE);
+        };
+        let mut image = self.image.lock().unwrap();
+        match function {
+            // holding and input registers are the same here
+            0x03 | 0x04 => {
+                if value == 0 || value > 125 || address as u32 + value as u32 > 0x10000 {
+                    return exception(ILLEGAL_DATA_VALUE);
+                }
+                if self.is_exception(&image, address, value) {
+                    return exception(ILLEGAL_DATA_ADDRESS);
+                }
+                self.update(&mut image, self.seconds_of_day());
+                let mut response = vec![function, value as u8 * 2];
+                for w in image.get(address, value) {
+                    response.extend_from_slice(&w.to_be_bytes());
+                }
+                response
+            }
+            0x06 => {
+                if self.is_exception(&image, address, 1) {
+                    return exception(ILLEGAL_DATA_ADDRESS);
+                }
+                image.set(address, &[value]);
+                pdu[..5].to_vec()
+            }
+            0x10 => {
+                let words: Vec<u16> = (0..value as usize).filter_map(|i| word(6 + i * 2)).collect();
+                if value == 0 || words.len() != value as usize || address as u32 + value as u32 > 0x10000 {
+                    return exception(ILLEGAL_DATA_VALUE);
+                }
+                if self.is_exception(&image, address, value) {
+                    return exception(ILLEGAL_DATA_ADDRESS);
+                }
+                image.set(address, &words);
+                pdu[..5].to_vec()
+            }
+            _ => exception(ILLEGAL_FUNCTION),
+        }
+    }
+
+    /// Answers the requests of one client until it disconnects
+    async fn handle(self, mut stream: TcpStream) -> io::Result<()> {
+        let mut header = [0u8; 7];
+        loop {
+            match stream.read_exact(&mut header).await {
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                Err(e) => return Err(e),
+            }
+            // the length covers the unit id and the PDU
+            let len = u16::from_be_bytes([header[4], header[5]]) as usize;
+            if !(2..=254).contains(&len) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("invalid frame length {}", len)));
+            }
+            let mut pdu = vec![0u8; len - 1];
+            stream.read_exact(&mut pdu).await?;
+            let response = self.respond(header[6], &pdu);
+            time::sleep(Duration::from_millis(self.config.latency_ms)).await;
+            let mut frame = header[..4].to_vec();
+            frame.extend_from_slice(&(response.len() as u16 + 1).to_be_bytes());
+            frame.push(header[6]);
+            frame.extend_from_slice(&response);
+            stream.write_all(&frame).await?;
+        }
+    }
+
+    /// Accepts clients forever, each one is served on its own task
+    pub async fn serve(self, listener: TcpListener) -> io::Result<()> {
+        loop {
+            let (stream, peer) = listener.accept().await?;
+            debug!("Simulator: client {} connected", peer);
+            let simulator = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = simulator.handle(stream).await {
+                    warn!("Simulator: client {} failed: {}", peer, e);
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{read_definitions, DEFAULT_DEFINITIONS};
+
+    fn simulator(config: SimulatorConfig) -> Simulator {
+        Simulator::new(&read_definitions(DEFAULT_DEFINITIONS).unwrap(), 1, config).unwrap()
+    }
+
+    fn read(sim: &Simulator, address: u16, count: u16) -> Vec<u8> {
+        let mut pdu = vec![0x03];
+        pdu.extend_from_slice(&address.to_be_bytes());
+        pdu.extend_from_slice(&count.to_be_bytes());
+        sim.respond(1, &pdu)
+    }
+
+    #[test]
+    fn follows_the_sun() {
+        assert_eq!(daylight(3.0 * 3600.0), 0.0);
+        assert_eq!(daylight(13.0 * 3600.0), 1.0);
+        assert!(daylight(9.0 * 3600.0) < daylight(11.0 * 3600.0));
+        // 8 kW peak over 14 hours
+        assert!((energy_today(8000.0, 23.0 * 3600.0) - 8.0 * 14.0 * 2.0 / PI).abs() < 1e-9);
+        assert_eq!(energy_today(8000.0, 5.0 * 3600.0), 0.0);
+    }
+
+    #[test]
+    fn serves_the_register_image() {
+        let noon = SimulatorConfig { pv_strings: 4, time: Some(NaiveTime::from_hms_opt(13, 0, 0).unwrap()), ..Default::default() };
+        let sim = simulator(noon);
+        // num_strings
+        assert_eq!(read(&sim, 30071, 1), vec![0x03, 2, 0, 4]);
+        // active power 8 kW * 0.97 with a gain of 1000
+        assert_eq!(read(&sim, 32080, 2), [vec![0x03, 4], 7760u32.to_be_bytes().to_vec()].concat());
+        // unused registers read as zero
+        assert_eq!(read(&sim, 39000, 1), vec![0x03, 2, 0, 0]);
+        let night = simulator(SimulatorConfig { time: Some(NaiveTime::from_hms_opt(2, 0, 0).unwrap()), ..Default::default() });
+        assert_eq!(read(&night, 32080, 2), vec![0x03, 4, 0, 0, 0, 0]);
+        assert_eq!(read(&night, 32089, 1), vec![0x03, 2, 0xA0, 0x00]);
+    }
+
+    #[test]
+    fn configured_values_win() {
+        let sim = simulator(SimulatorConfig {
+            values: vec![("active_power".to_string(), "-1.5".to_string()), ("model_ident".to_string(), "SUN2000-10KTL-M1".to_string())],
+            ..Default::default()
+        });
+        assert_eq!(read(&sim, 32080, 2), [vec![0x03, 4], (-1500i32).to_be_bytes().to_vec()].concat());
+        assert_eq!(&read(&sim, 30000, 1), &[0x03, 2, b'S', b'U']);
+        let defs = read_definitions(DEFAULT_DEFINITIONS).unwrap();
+        let unknown = SimulatorConfig { values: vec![("nope".to_string(), "1".to_string())], ..Default::default() };
+        assert!(Simulator::new(&defs, 1, unknown).is_err());
+        let invalid = SimulatorConfig { values: vec![("num_strings".to_string(), "-1".to_string())], ..Default::default() };
+        assert!(Simulator::new(&defs, 1, invalid).is_err());
+    }
+
+    #[test]
+    fn injects_exceptions() {
+        let sim = simulator(SimulatorConfig { exceptions: vec![32085], ..Default::default() });
+        assert_eq!(read(&sim, 32080, 10), vec![0x83, ILLEGAL_DATA_ADDRESS]);
+        assert_eq!(read(&sim, 32086, 10)[0], 0x03);
+        assert_eq!(read(&sim, 32080, 126), vec![0x83, ILLEGAL_DATA_VALUE]);
+        assert_eq!(sim.respond(2, &[0x03, 0x75, 0x30, 0, 1]), vec![0x83, GATEWAY_TARGET_FAILED]);
+        assert_eq!(sim.respond(1, &[0x2B, 0x0E, 0x01, 0x00, 0x00]), vec![0xAB, ILLEGAL_FUNCTION]);
+    }
+
+    #[test]
+    fn keeps_writes() {
+        let sim = simulator(SimulatorConfig::default());
+        // active_power_derating = 50.5 %
+        assert_eq!(sim.respond(1, &[0x06, 0x9C, 0xBD, 0x01, 0xF9]), vec![0x06, 0x9C, 0xBD, 0x01, 0xF9]);
+        assert_eq!(read(&sim, 40125, 1), vec![0x03, 2, 0x01, 0xF9]);
+        assert_eq!(sim.respond(1, &[0x10, 0x9C, 0xBE, 0, 2, 4, 0, 1, 0x11, 0x70]), vec![0x10, 0x9C, 0xBE, 0, 2]);
+        assert_eq!(read(&sim, 40126, 2), vec![0x03, 4, 0, 1, 0x11, 0x70]);
+    }
+
+    #[test]
+    fn serves_the_last_register() {
+        let sim = simulator(SimulatorConfig::default());
+        assert_eq!(sim.respond(1, &[0x10, 0xFF, 0xFE, 0, 2, 4, 0, 7, 0, 8]), vec![0x10, 0xFF, 0xFE, 0, 2]);
+        assert_eq!(read(&sim, 65534, 2), vec![0x03, 4, 0, 7, 0, 8]);
+        assert_eq!(read(&sim, 65535, 1), vec![0x03, 2, 0, 8]);
+        assert_eq!(read(&sim, 65535, 2), vec![0x83, ILLEGAL_DATA_VALUE]);
+        let mut image = Image::default();
+        image.set(65535, &[1, 2]);
+        assert_eq!(image.registers.len(), 1);
+    }
+
+    #[test]
+    fn rejects_undefined_registers() {
+        let sim = simulator(SimulatorConfig { reject_undefined: true, ..Default::default() });
+        assert_eq!(read(&sim, 39000, 1), vec![0x83, ILLEGAL_DATA_ADDRESS]);
+        // state_1 is defined but the register after it isn't
+        assert_eq!(read(&sim, 32000, 1)[0], 0x03);
+        assert_eq!(read(&sim, 32000, 2), vec![0x83, ILLEGAL_DATA_ADDRESS]);
+        assert_eq!(sim.respond(1, &[0x06, 0x7D, 0x01, 0, 1]), vec![0x86, ILLEGAL_DATA_ADDRESS]);
+    }
+}
@@ -0,0 +1,340 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::task::JoinHandle;
+
+use crate::config::Config;
+use crate::connection::{Backoff, ConnectionState, ConnectionStats};
+use crate::events::Event;
+use crate::parser::types::*;
+
+pub mod influx;
+pub mod mqtt;
+pub mod prometheus;
+pub mod redis;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Category {
+    General,
+    Storage,
+    Pgs,
+    Pv,
+}
+
+impl Category {
+    /// Short name used in keys, topics and measurement names
+    pub fn key(&self) -> &'static str {
+        match self {
+            Category::General => "general",
+            Category::Storage => "storage",
+            Category::Pgs => "pgs",
+            Category::Pv => "pv",
+        }
+    }
+
+    pub fn from_key(key: &str) -> Option<Category> {
+        [Category::General, Category::Storage, Category::Pgs, Category::Pv].into_iter().find(|c| c.key() == key)
+    }
+}
+
+/// A single value of a completed gathering cycle
+#[derive(Debug, Clone)]
+pub struct Sample {
+    pub name: String,
+    pub category: Category,
+    /// Battery pack the value belongs to, if any
+    pub pack: Option<u8>,
+    /// PV string the value belongs to, if any
+    pub string: Option<u8>,
+    pub address: u16,
+    pub raw: PVSignalDataType,
+    /// Raw value with the gain applied, `None` for text, states and unknowns
+    pub scaled: Option<f64>,
+    /// Named state of `ENUM` and `BITS` registers
+    pub label: Option<String>,
+    pub unit: String,
+    pub gain: u16,
+    /// Unix millis of the read that produced the value
+    pub time: i64,
+}
+
+/// Splits the number off names like `pack1_soc` or `pv_0_voltage`, see
+/// `gen_batdata` and `gen_pvdata`
+fn split_ident<'a>(name: &'a str, prefix: &str) -> Option<(u8, &'a str)> {
+    let (ident, rest) = name.strip_prefix(prefix)?.split_once('_')?;
+    Some((ident.parse().ok()?, rest))
+}
+
+impl Sample {
+    pub fn from_signal(category: Category, signal: &PVSignal) -> Sample {
+        let (pack, string) = match category {
+            Category::Storage => (split_ident(&signal.name, "pack").map(|x| x.0), None),
+            Category::Pv => (None, split_ident(&signal.name, "pv_").map(|x| x.0)),
+            _ => (None, None),
+        };
+        Sample {
+            name: signal.name.clone(),
+            category,
+            pack,
+            string,
+            address: signal.address,
+            raw: signal.data.clone(),
+            scaled: signal.scaled(),
+            label: signal.data.label(),
+            unit: signal.unit.clone(),
+            gain: signal.gain,
+            time: signal.time,
+        }
+    }
+
+    /// Name without the pack/string prefix, e.g. `soc` for `pack1_soc`
+    pub fn field(&self) -> &str {
+        match (self.pack, self.string) {
+            (Some(_), _) => split_ident(&self.name, "pack").map(|x| x.1).unwrap_or(&self.name),
+            (_, Some(_)) => split_ident(&self.name, "pv_").map(|x| x.1).unwrap_or(&self.name),
+            _ => &self.name,
+        }
+    }
+}
+
+/// Values for the tests of the sinks
+#[cfg(test)]
+pub(crate) mod test_util {
+    use super::*;
+
+    /// A value read at 1700000000000
+    pub fn sample(name: &str, category: Category, raw: PVSignalDataType, gain: u16) -> Sample {
+        let signal = PVSignal {
+            data: raw,
+            address: 0,
+            length: 1,
+            name: name.to_string(),
+            unit: "".to_string(),
+            gain,
+            time: 1700000000000,
+        };
+        Sample::from_signal(category, &signal)
+    }
+
+    /// A cycle with text, unknowns, packs and strings
+    pub fn batch() -> SampleBatch {
+        SampleBatch {
+            base_key: "roof top".to_string(),
+            samples: vec![
+                sample("active_power", Category::General, PVSignalDataType::I32(-1500), 1000),
+                sample("model_ident", Category::General, PVSignalDataType::STR("SUN2000 \"8KTL\"".to_string()), 1),
+                sample("alarm_1", Category::General, PVSignalDataType::UNK(5), 1),
+                sample("pack1_soc", Category::Storage, PVSignalDataType::U16(805), 10),
+                sample("soc", Category::Storage, PVSignalDataType::U16(790), 10),
+                sample("pv_0_voltage", Category::Pv, PVSignalDataType::I16(3605), 10),
+                sample("pv_0_current", Category::Pv, PVSignalDataType::I16(512), 100),
+            ],
+        }
+    }
+}
+
+/// Everything read in one gathering cycle
+#[derive(Debug, Clone)]
+pub struct SampleBatch {
+    pub base_key: String,
+    pub samples: Vec<Sample>,
+}
+
+/// Health of the logger itself, published after every cycle
+#[derive(Debug, Clone)]
+pub struct Status {
+    pub base_key: String,
+    pub state: ConnectionState,
+    pub stats: ConnectionStats,
+    /// Time spent on Modbus requests per category in the last successful read
+    pub read_durations: BTreeMap<Category, Duration>,
+    /// Unix millis of the last cycle that read every signal
+    pub last_cycle: i64,
+}
+
+#[derive(Debug)]
+pub struct SinkError(pub String);
+
+impl fmt::Display for SinkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl From<::redis::RedisError> for SinkError {
+    fn from(err: ::redis::RedisError) -> Self {
+        SinkError(err.to_string())
+    }
+}
+
+/// Destination for the gathered values. Sinks are independent of each other,
+/// a failing sink doesn't keep the others from receiving the batch.
+#[async_trait]
+pub trait Sink: fmt::Debug + Send {
+    fn name(&self) -> &str;
+
+    /// Checks that the sink is reachable, retried until it succeeds before
+    /// anything is sent
+    async fn init(&mut self) -> Result<(), SinkError> {
+        Ok(())
+    }
+
+    async fn send(&mut self, batch: &SampleBatch) -> Result<(), SinkError>;
+
+    /// Publishes the logger health, called after every cycle, also when reading failed
+    async fn send_status(&mut self, _status: &Status) -> Result<(), SinkError> {
+        Ok(())
+    }
+
+    /// Publishes alarm and state events seen in the last cycle, never called with an empty slice
+    async fn send_events(&mut self, _base_key: &str, _events: &[Event]) -> Result<(), SinkError> {
+        Ok(())
+    }
+}
+
+/// Messages waiting for a sink, handled in order
+#[derive(Debug)]
+enum Message {
+    Data(SampleBatch),
+    Status(Status),
+    Events(String, Vec<Event>),
+}
+
+/// Messages a sink may fall behind by before new ones are dropped
+const QUEUE_LEN: usize = 32;
+/// Longest wait between two attempts to initialize a sink
+const MAX_INIT_DELAY: Duration = Duration::from_secs(300);
+
+/// Queues messages for a sink running on its own task, so a slow sink
+/// never holds up reading from the inverters. Every device gets a clone.
+#[derive(Debug, Clone)]
+pub struct SinkHandle {
+    name: String,
+    tx: mpsc::Sender<Message>,
+}
+
+async fn handle(sink: &mut dyn Sink, message: Message) {
+    match message {
+        Message::Data(batch) => {
+            let start = Instant::now();
+            match sink.send(&batch).await {
+                Ok(()) => debug!("Sink {} took {}ms", sink.name(), start.elapsed().as_millis()),
+                Err(e) => error!("Sink {} failed: {}", sink.name(), e),
+            }
+        }
+        Message::Status(status) => {
+            if let Err(e) = sink.send_status(&status).await {
+                warn!("Unable to publish connection status to {}: {}", sink.name(), e);
+            }
+        }
+        Message::Events(base_key, events) => {
+            if let Err(e) = sink.send_events(&base_key, &events).await {
+                warn!("Unable to publish events to {}: {}", sink.name(), e);
+            }
+        }
+    }
+}
+
+/// Initializes `sink` unless it already is or the backoff delay hasn't
+/// passed yet, returns whether it can be used
+async fn init(sink: &mut dyn Sink, ready: &mut bool, backoff: &mut Backoff, retry_at: &mut Instant) -> bool {
+    if *ready || Instant::now() < *retry_at {
+        return *ready;
+    }
+    match sink.init().await {
+        Ok(()) => {
+            info!("Sink {} is ready", sink.name());
+            *ready = true;
+        }
+        Err(e) => {
+            let delay = backoff.next_delay();
+            warn!("Sink {} isn't usable, retrying in {}s: {}", sink.name(), delay.as_secs(), e);
+            *retry_at = Instant::now() + delay;
+        }
+    }
+    *ready
+}
+
+impl SinkHandle {
+    /// Starts the task feeding `sink`, it ends once every handle is dropped
+    /// and the queue has been worked off. Until the sink has been initialized
+    /// messages are dropped.
+    pub fn spawn(mut sink: Box<dyn Sink>) -> (SinkHandle, JoinHandle<()>) {
+        let (tx, mut rx) = mpsc::channel(QUEUE_LEN);
+        let name = sink.name().to_string();
+        let task = tokio::spawn(async move {
+            let mut backoff = Backoff::new(Duration::from_secs(1), MAX_INIT_DELAY);
+            let mut retry_at = Instant::now();
+            let mut ready = false;
+            init(sink.as_mut(), &mut ready, &mut backoff, &mut retry_at).await;
+            while let Some(message) = rx.recv().await {
+                if init(sink.as_mut(), &mut ready, &mut backoff, &mut retry_at).await {
+                    handle(sink.as_mut(), message).await;
+                } else {
+                    debug!("Sink {} isn't ready, dropping data", sink.name());
+                }
+            }
+            debug!("Sink {} stopped", sink.name());
+        });
+        (SinkHandle { name, tx }, task)
+    }
+
+    fn queue(&self, message: Message) {
+        match self.tx.try_send(message) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => warn!("Sink {} is falling behind, dropping data", self.name),
+            Err(TrySendError::Closed(_)) => warn!("Sink {} has stopped", self.name),
+        }
+    }
+
+    pub fn send(&self, batch: SampleBatch) {
+        self.queue(Message::Data(batch));
+    }
+
+    pub fn send_status(&self, status: Status) {
+        self.queue(Message::Status(status));
+    }
+
+    pub fn send_events(&self, base_key: String, events: Vec<Event>) {
+        self.queue(Message::Events(base_key, events));
+    }
+}
+
+/// Creates every sink enabled in the config, fails if the metrics can't be served
+pub fn from_config(config: &Config) -> Result<Vec<Box<dyn Sink>>, SinkError> {
+    let mut sinks: Vec<Box<dyn Sink>> = Vec::new();
+    if config.redis.enabled {
+        sinks.push(Box::new(self::redis::RedisSink::new(&config.redis)));
+    }
+    if config.influx.enabled {
+        sinks.push(Box::new(influx::InfluxSink::new(&config.influx)));
+    }
+    if config.mqtt.enabled {
+        sinks.push(Box::new(mqtt::MqttSink::new(&config.mqtt)));
+    }
+    if config.prometheus.enabled {
+        let sink = prometheus::PrometheusSink::new(&config.prometheus)
+            .map_err(|e| SinkError(format!("Unable to serve metrics on {}: {}", config.prometheus.listen, e)))?;
+        sinks.push(Box::new(sink));
+    }
+    Ok(sinks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fails_when_the_metrics_port_is_taken() {
+        let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut config = Config::default();
+        config.redis.enabled = false;
+        config.prometheus.enabled = true;
+        config.prometheus.listen = taken.local_addr().unwrap().to_string();
+        let err = from_config(&config).unwrap_err();
+        assert!(err.0.starts_with("Unable to serve metrics on 127.0.0.1:"), "{}", err);
+    }
+}
@@ -0,0 +1,205 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use async_trait::async_trait;
+
+use crate::config::{InfluxConfig, ValueMode};
+use crate::parser::types::*;
+use super::{Sample, SampleBatch, Sink, SinkError};
+
+/// Posts every cycle as InfluxDB line protocol to `/api/v2/write`
+#[derive(Debug)]
+pub struct InfluxSink {
+    agent: ureq::Agent,
+    url: String,
+    org: String,
+    bucket: String,
+    token: String,
+    values: ValueMode,
+}
+
+/// Escapes measurement names, tag keys/values and field keys
+fn escape_key(s: &str) -> String {
+    s.replace('\\', "\\\\").replace(',', "\\,").replace('=', "\\=").replace(' ', "\\ ")
+}
+
+/// Fields for a sample, numbers go in as integers when raw and as floats when scaled
+fn fields(sample: &Sample, values: ValueMode) -> Vec<String> {
+    let key = escape_key(sample.field());
+    let raw = match &sample.raw {
+        PVSignalDataType::STR(x) => return vec![format!("{}=\"{}\"", key, x.replace('\\', "\\\\").replace('"', "\\\""))],
+        PVSignalDataType::UNK(x) => return vec![format!("{}={}i", key, x)],
+        // the code is kept as an integer, the name goes next to it
+        PVSignalDataType::ENUM(x, _) | PVSignalDataType::BITS(x, _) => {
+            return vec![format!("{}={}i", key, x), format!("{}_state=\"{}\"", key, sample.label.as_deref().unwrap_or_default().replace('"', "\\\""))];
+        }
+        PVSignalDataType::U16(x) => format!("{}i", x),
+        PVSignalDataType::I16(x) => format!("{}i", x),
+        PVSignalDataType::U32(x) => format!("{}i", x),
+        PVSignalDataType::I32(x) => format!("{}i", x),
+    };
+    let scaled = format!("{:?}", sample.scaled.unwrap());
+    match values {
+        ValueMode::Raw => vec![format!("{}={}", key, raw)],
+        ValueMode::Scaled => vec![format!("{}={}", key, scaled)],
+        ValueMode::Both => vec![format!("{}={}", key, scaled), format!("{}_raw={}", key, raw)],
+    }
+}
+
+/// Renders a batch with one line per category, battery pack and PV string
+pub fn render(batch: &SampleBatch, values: ValueMode) -> String {
+    // (measurement, tags) -> (latest time, fields)
+    let mut lines: BTreeMap<(&str, String), (i64, Vec<String>)> = BTreeMap::new();
+    for s in batch.samples.iter() {
+        let mut tags = format!("base={}", escape_key(&batch.base_key));
+        if let Some(pack) = s.pack {
+            tags.push_str(&format!(",pack={}", pack));
+        }
+        if let Some(string) = s.string {
+            tags.push_str(&format!(",string={}", string));
+        }
+        let line = lines.entry((s.category.key(), tags)).or_insert((0, Vec::new()));
+        line.0 = line.0.max(s.time);
+        line.1.extend(fields(s, values));
+    }
+
+    let mut out = String::new();
+    for ((measurement, tags), (time, fields)) in lines {
+        out.push_str(&format!("{},{} {} {}\n", escape_key(measurement), tags, fields.join(","), time));
+    }
+    out
+}
+
+impl InfluxSink {
+    pub fn new(config: &InfluxConfig) -> InfluxSink {
+        InfluxSink {
+            agent: ureq::AgentBuilder::new().timeout(Duration::from_secs(config.timeout_secs)).build(),
+            url: format!("{}/api/v2/write", config.url.trim_end_matches('/')),
+            org: config.org.clone(),
+            bucket: config.bucket.clone(),
+            token: config.token.clone(),
+            values: config.values,
+        }
+    }
+}
+
+#[async_trait]
+impl Sink for InfluxSink {
+    fn name(&self) -> &str {
+        "influx"
+    }
+
+    async fn send(&mut self, batch: &SampleBatch) -> Result<(), SinkError> {
+        let body = render(batch, self.values);
+        let mut req = self.agent.post(&self.url)
+            .query("bucket", &self.bucket)
+            .query("precision", "ms")
+            .set("Content-Type", "text/plain; charset=utf-8");
+        if !self.org.is_empty() {
+            req = req.query("org", &self.org);
+        }
+        if !self.token.is_empty() {
+            req = req.set("Authorization", &format!("Token {}", self.token));
+        }
+        debug!("Saving data to influx");
+        // ureq blocks, keep it off the runtime threads
+        let res = tokio::task::spawn_blocking(move || match req.send_string(&body) {
+            Ok(_) => Ok(()),
+            Err(ureq::Error::Status(code, resp)) => {
+                Err(SinkError(format!("InfluxDB returned {}: {}", code, resp.into_string().unwrap_or_default())))
+            }
+            Err(e) => Err(SinkError(e.to_string())),
+        }).await;
+        res.unwrap_or_else(|e| Err(SinkError(e.to_string())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sinks::test_util::batch;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn renders_line_protocol() {
+        let lines = render(&batch(), ValueMode::Scaled);
+        assert_eq!(lines, "\
+general,base=roof\\ top active_power=-1.5,model_ident=\"SUN2000 \\\"8KTL\\\"\",alarm_1=5i 1700000000000
+pv,base=roof\\ top,string=0 voltage=360.5,current=5.12 1700000000000
+storage,base=roof\\ top soc=79.0 1700000000000
+storage,base=roof\\ top,pack=1 soc=80.5 1700000000000
+");
+    }
+
+    #[test]
+    fn renders_raw_values_as_integers() {
+        let lines = render(&batch(), ValueMode::Raw);
+        assert!(lines.starts_with("general,base=roof\\ top active_power=-1500i,"));
+        let lines = render(&batch(), ValueMode::Both);
+        assert!(lines.contains("pv,base=roof\\ top,string=0 voltage=360.5,voltage_raw=3605i,current=5.12,current_raw=512i 1700000000000\n"));
+        assert!(lines.contains("alarm_1=5i "));
+    }
+
+    /// Accepts a single request and returns its request line, headers and body
+    fn stub_server(status: &'static str) -> (String, thread::JoinHandle<(String, String)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut head = String::new();
+            let mut length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if let Some(v) = line.to_lowercase().strip_prefix("content-length:") {
+                    length = v.trim().parse().unwrap();
+                }
+                if line == "\r\n" {
+                    break;
+                }
+                head.push_str(&line);
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            let response = format!("HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status);
+            reader.get_mut().write_all(response.as_bytes()).unwrap();
+            (head, String::from_utf8(body).unwrap())
+        });
+        (url, handle)
+    }
+
+    fn sink(url: String) -> InfluxSink {
+        InfluxSink::new(&InfluxConfig {
+            enabled: true,
+            url,
+            org: "home".to_string(),
+            bucket: "solar".to_string(),
+            token: "secret".to_string(),
+            timeout_secs: 5,
+            values: ValueMode::Scaled,
+        })
+    }
+
+    #[tokio::test]
+    async fn posts_to_write_endpoint() {
+        let (url, server) = stub_server("204 No Content");
+        sink(url).send(&batch()).await.unwrap();
+        let (head, body) = server.join().unwrap();
+        let request_line = head.lines().next().unwrap();
+        assert!(request_line.starts_with("POST /api/v2/write?"));
+        assert!(request_line.contains("bucket=solar") && request_line.contains("org=home") && request_line.contains("precision=ms"));
+        assert!(head.contains("Authorization: Token secret"));
+        assert_eq!(body, render(&batch(), ValueMode::Scaled));
+    }
+
+    #[tokio::test]
+    async fn reports_server_errors() {
+        let (url, server) = stub_server("401 Unauthorized");
+        let res = sink(url).send(&batch()).await;
+        server.join().unwrap();
+        assert!(res.unwrap_err().0.contains("401"));
+    }
+}
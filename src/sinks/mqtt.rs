@@ -0,0 +1,284 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use rumqttc::{AsyncClient, MqttOptions, QoS};
+use serde_json::{json, Value};
+
+use crate::config::{MqttConfig, ValueMode};
+use crate::parser::{is_static, types::*};
+use crate::events::Event;
+use super::{Sample, SampleBatch, Sink, SinkError};
+
+/// Publishes every signal to its own topic, optionally announcing them to
+/// Home Assistant via MQTT discovery
+pub struct MqttSink {
+    client: AsyncClient,
+    topic_prefix: String,
+    discovery: bool,
+    discovery_prefix: String,
+    discovered: HashSet<String>,
+    /// Model of each device, it is only read at startup
+    models: HashMap<String, String>,
+    values: ValueMode,
+}
+
+impl fmt::Debug for MqttSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MqttSink")
+            .field("topic_prefix", &self.topic_prefix)
+            .field("discovery", &self.discovery)
+            .finish()
+    }
+}
+
+/// Topics and ids may only contain a safe subset of characters
+fn sanitize(s: &str) -> String {
+    s.chars().map(|c| if c.is_ascii_alphanumeric() || c == '_' || c == '-' { c } else { '_' }).collect()
+}
+
+fn payload(sample: &Sample, values: ValueMode) -> String {
+    if let Some(label) = &sample.label {
+        if values != ValueMode::Raw {
+            return label.clone();
+        }
+    }
+    match (&sample.raw, sample.scaled) {
+        (PVSignalDataType::STR(x), _) => x.clone(),
+        (_, Some(scaled)) if values != ValueMode::Raw => scaled.to_string(),
+        (raw, _) => raw.to_string(),
+    }
+}
+
+fn device_class(sample: &Sample) -> Option<&'static str> {
+    match sample.unit.as_str() {
+        "V" => Some("voltage"),
+        "A" => Some("current"),
+        "W" | "kW" => Some("power"),
+        "kVA" => Some("apparent_power"),
+        "kVar" => Some("reactive_power"),
+        "kWh" => Some("energy"),
+        "°C" => Some("temperature"),
+        "Hz" => Some("frequency"),
+        "%" if sample.field() == "soc" => Some("battery"),
+        "s" | "min" => Some("duration"),
+        _ => None,
+    }
+}
+
+fn state_class(sample: &Sample) -> Option<&'static str> {
+    sample.scaled?;
+    // nameplate values like the rated powers have no history worth keeping
+    if is_static(sample.address) {
+        return None;
+    }
+    match sample.unit.as_str() {
+        // energy counters like acc_energy_yield and total_charge only ever grow
+        // (day_* counters reset at midnight, which total_increasing handles)
+        "kWh" => Some("total_increasing"),
+        _ => Some("measurement"),
+    }
+}
+
+/// Home Assistant discovery topic and config payload for a sample
+pub fn discovery_config(discovery_prefix: &str, state_topic: &str, base_key: &str, model: Option<&str>, values: ValueMode, sample: &Sample) -> (String, Value) {
+    let node = sanitize(base_key);
+    let object = sanitize(&format!("{}_{}", sample.category.key(), sample.name));
+    let mut config = json!({
+        "name": sample.name.replace('_', " "),
+        "unique_id": format!("{}_{}", node, object),
+        "object_id": format!("{}_{}", node, object),
+        "state_topic": state_topic,
+        "device": {
+            "identifiers": [node],
+            "name": format!("SUN2000 {}", base_key),
+            "model": model.unwrap_or("SUN2000"),
+        },
+    });
+    if !sample.unit.is_empty() {
+        config["unit_of_measurement"] = json!(sample.unit);
+    }
+    if let Some(class) = device_class(sample) {
+        config["device_class"] = json!(class);
+    }
+    if let Some(class) = state_class(sample) {
+        config["state_class"] = json!(class);
+    }
+    if sample.scaled.is_some() {
+        // a gain of 10 means one decimal place and so on
+        config["suggested_display_precision"] = json!((sample.gain.max(1) as f64).log10().ceil() as u32);
+        // raw values are published as read, let Home Assistant apply the gain
+        if values == ValueMode::Raw && sample.gain > 1 {
+            config["value_template"] = json!(format!("{{{{ value | float / {} }}}}", sample.gain));
+        }
+    }
+    if is_static(sample.address) {
+        config["entity_category"] = json!("diagnostic");
+    }
+    (format!("{}/sensor/{}/{}/config", discovery_prefix, node, object), config)
+}
+
+impl MqttSink {
+    pub fn new(config: &MqttConfig) -> MqttSink {
+        let mut options = MqttOptions::new(config.client_id.clone(), config.host.clone(), config.port);
+        options.set_keep_alive(Duration::from_secs(30));
+        if !config.username.is_empty() {
+            options.set_credentials(config.username.clone(), config.password.clone());
+        }
+        let (client, mut eventloop) = AsyncClient::new(options, 1000);
+        // the event loop has to be polled for anything to be sent, it
+        // reconnects on its own when polled after an error
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = eventloop.poll().await {
+                    warn!("MQTT connection error: {}", e);
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+                }
+            }
+        });
+        MqttSink {
+            client,
+            topic_prefix: config.topic_prefix.trim_end_matches('/').to_string(),
+            discovery: config.discovery,
+            discovery_prefix: config.discovery_prefix.trim_end_matches('/').to_string(),
+            discovered: HashSet::new(),
+            models: HashMap::new(),
+            values: config.values,
+        }
+    }
+
+    fn state_topic(&self, base_key: &str, sample: &Sample) -> String {
+        format!("{}/{}/{}/{}", self.topic_prefix, sanitize(base_key), sample.category.key(), sanitize(&sample.name))
+    }
+
+    fn publish(&self, topic: String, retain: bool, payload: String) -> Result<(), SinkError> {
+        self.client.try_publish(topic, QoS::AtLeastOnce, retain, payload).map_err(|e| SinkError(e.to_string()))
+    }
+}
+
+#[async_trait]
+impl Sink for MqttSink {
+    fn name(&self) -> &str {
+        "mqtt"
+    }
+
+    async fn send(&mut self, batch: &SampleBatch) -> Result<(), SinkError> {
+        if let Some(model) = batch.samples.iter().find(|s| s.name == "model_ident") {
+            self.models.insert(batch.base_key.clone(), payload(model, self.values));
+        }
+        let model = self.models.get(&batch.base_key).cloned();
+        if self.discovery {
+            for s in batch.samples.iter() {
+                let topic = self.state_topic(&batch.base_key, s);
+                if self.discovered.contains(&topic) {
+                    continue;
+                }
+                let (config_topic, config) = discovery_config(&self.discovery_prefix, &topic, &batch.base_key, model.as_deref(), self.values, s);
+                self.publish(config_topic, true, config.to_string())?;
+                self.discovered.insert(topic);
+            }
+        }
+
+        debug!("Saving data to mqtt");
+        for s in batch.samples.iter() {
+            // static values are retained so late subscribers still get them
+            let topic = self.state_topic(&batch.base_key, s);
+            let retain = is_static(s.address);
+            if self.values == ValueMode::Both && s.raw.as_f64().is_some() {
+                self.publish(format!("{}/raw", topic), retain, payload(s, ValueMode::Raw))?;
+            }
+            self.publish(topic, retain, payload(s, self.values))?;
+        }
+        Ok(())
+    }
+
+    async fn send_events(&mut self, base_key: &str, events: &[Event]) -> Result<(), SinkError> {
+        let topic = format!("{}/{}/events", self.topic_prefix, sanitize(base_key));
+        for e in events {
+            let event = json!({
+                "time": e.time,
+                "category": e.category.key(),
+                "signal": e.signal,
+                "kind": e.kind.key(),
+                "state": e.state,
+                "previous": e.previous,
+            });
+            self.publish(topic.clone(), false, event.to_string())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sinks::Category;
+
+    fn sample(name: &str, category: Category, address: u16, raw: PVSignalDataType, unit: &str, gain: u16) -> Sample {
+        let signal = PVSignal {
+            data: raw,
+            address,
+            length: 1,
+            name: name.to_string(),
+            unit: unit.to_string(),
+            gain,
+            time: 0,
+        };
+        Sample::from_signal(category, &signal)
+    }
+
+    #[test]
+    fn energy_counters_are_total_increasing() {
+        let s = sample("acc_energy_yield", Category::General, 32106, PVSignalDataType::U32(123456), "kWh", 100);
+        let (topic, config) = discovery_config("homeassistant", "solar/roof/general/acc_energy_yield", "roof", Some("SUN2000-8KTL-M1"), ValueMode::Scaled, &s);
+        assert_eq!(topic, "homeassistant/sensor/roof/general_acc_energy_yield/config");
+        assert_eq!(config["device_class"], "energy");
+        assert_eq!(config["state_class"], "total_increasing");
+        assert_eq!(config["unit_of_measurement"], "kWh");
+        assert_eq!(config["suggested_display_precision"], 2);
+        assert_eq!(config["device"]["model"], "SUN2000-8KTL-M1");
+        assert_eq!(payload(&s, ValueMode::Scaled), "1234.56");
+        assert!(config.get("value_template").is_none());
+
+        let s = sample("total_charge", Category::Storage, 37066, PVSignalDataType::U32(1), "kWh", 100);
+        let (_, config) = discovery_config("homeassistant", "t", "roof", None, ValueMode::Scaled, &s);
+        assert_eq!(config["state_class"], "total_increasing");
+    }
+
+    #[test]
+    fn measurements_and_text() {
+        let s = sample("pack1_soc", Category::Storage, 38229, PVSignalDataType::U16(805), "%", 10);
+        let (_, config) = discovery_config("homeassistant", "t", "roof", None, ValueMode::Scaled, &s);
+        assert_eq!(config["device_class"], "battery");
+        assert_eq!(config["state_class"], "measurement");
+
+        let s = sample("model_ident", Category::General, 30000, PVSignalDataType::STR("SUN2000".to_string()), "", 1);
+        let (_, config) = discovery_config("homeassistant", "t", "roof", None, ValueMode::Scaled, &s);
+        assert!(config.get("state_class").is_none());
+        assert!(config.get("unit_of_measurement").is_none());
+        assert_eq!(config["entity_category"], "diagnostic");
+    }
+
+    #[test]
+    fn static_values_have_no_state_class() {
+        let s = sample("rated_power", Category::General, 30073, PVSignalDataType::U32(8000), "kW", 1000);
+        let (_, config) = discovery_config("homeassistant", "t", "roof", None, ValueMode::Raw, &s);
+        assert!(config.get("state_class").is_none());
+        assert_eq!(config["value_template"], "{{ value | float / 1000 }}");
+        assert_eq!(config["suggested_display_precision"], 3);
+        let s = sample("rated_capacity", Category::General, 30080, PVSignalDataType::U32(1000), "kWh", 100);
+        let (_, config) = discovery_config("homeassistant", "t", "roof", None, ValueMode::Scaled, &s);
+        assert!(config.get("state_class").is_none());
+        assert_eq!(config["device_class"], "energy");
+    }
+
+    #[test]
+    fn raw_values_get_a_template() {
+        let s = sample("active_power", Category::General, 32080, PVSignalDataType::I32(-1500), "kW", 1000);
+        let (_, config) = discovery_config("homeassistant", "t", "roof", None, ValueMode::Raw, &s);
+        assert_eq!(config["value_template"], "{{ value | float / 1000 }}");
+        assert_eq!(payload(&s, ValueMode::Raw), "-1500");
+        assert_eq!(payload(&s, ValueMode::Both), "-1.5");
+    }
+}
@@ -0,0 +1,289 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use async_trait::async_trait;
+
+use crate::config::{PrometheusConfig, ValueMode};
+use crate::connection::ConnectionState;
+use crate::parser::types::*;
+use super::{Sample, SampleBatch, Sink, SinkError, Status};
+
+/// Time a scraper gets to send its request and read the response
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Latest batch and status of every device, by base key
+#[derive(Debug, Default)]
+struct Latest {
+    batches: BTreeMap<String, SampleBatch>,
+    statuses: BTreeMap<String, Status>,
+}
+
+/// Serves the last cycle and the logger health on `/metrics` for scraping
+#[derive(Debug)]
+pub struct PrometheusSink {
+    latest: Arc<Mutex<Latest>>,
+}
+
+fn metric_name(s: &str) -> String {
+    s.chars().map(|c| if c.is_ascii_alphanumeric() || c == '_' { c } else { '_' }).collect()
+}
+
+fn label_value(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn labels(base_key: &str, sample: &Sample) -> String {
+    let mut out = format!("base=\"{}\"", label_value(base_key));
+    if let Some(pack) = sample.pack {
+        let _ = write!(out, ",pack=\"{}\"", pack);
+    }
+    if let Some(string) = sample.string {
+        let _ = write!(out, ",string=\"{}\"", string);
+    }
+    out
+}
+
+/// One gauge per signal, strings become labels of an info metric. Every
+/// metric is listed once with a line per device.
+pub fn render_samples<'a>(batches: impl IntoIterator<Item = &'a SampleBatch>, values: ValueMode) -> String {
+    // metric name -> (unit, lines)
+    let mut gauges: BTreeMap<String, (&str, Vec<String>)> = BTreeMap::new();
+    let mut infos = Vec::new();
+    for batch in batches {
+        let mut info = format!("base=\"{}\"", label_value(&batch.base_key));
+        for s in batch.samples.iter() {
+            let name = metric_name(&format!("solar_{}_{}", s.category.key(), s.field()));
+            let (raw, scaled) = match &s.raw {
+                PVSignalDataType::STR(x) => {
+                    let _ = write!(info, ",{}=\"{}\"", metric_name(&format!("{}_{}", s.category.key(), s.name)), label_value(x));
+                    continue;
+                }
+                PVSignalDataType::UNK(x) => (*x as f64, None),
+                x @ (PVSignalDataType::ENUM(..) | PVSignalDataType::BITS(..)) => {
+                    let _ = write!(info, ",{}=\"{}\"", metric_name(&format!("{}_{}", s.category.key(), s.name)), label_value(s.label.as_deref().unwrap_or_default()));
+                    (x.as_f64().unwrap(), None)
+                }
+                x => (x.as_f64().unwrap(), s.scaled),
+            };
+            let mut metrics = Vec::new();
+            match (values, scaled) {
+                (ValueMode::Raw, _) | (_, None) => metrics.push((name, raw)),
+                (ValueMode::Scaled, Some(scaled)) => metrics.push((name, scaled)),
+                (ValueMode::Both, Some(scaled)) => {
+                    metrics.push((format!("{}_raw", name), raw));
+                    metrics.push((name, scaled));
+                }
+            }
+            for (name, value) in metrics {
+                let entry = gauges.entry(name.clone()).or_insert((&s.unit, Vec::new()));
+                entry.1.push(format!("{}{{{}}} {}", name, labels(&batch.base_key, s), value));
+            }
+        }
+        infos.push(info);
+    }
+
+    let mut out = String::new();
+    let _ = writeln!(out, "# HELP solar_info Text values read from the inverter");
+    let _ = writeln!(out, "# TYPE solar_info gauge");
+    for info in infos {
+        let _ = writeln!(out, "solar_info{{{}}} 1", info);
+    }
+    for (name, (unit, lines)) in gauges {
+        let _ = writeln!(out, "# HELP {} Inverter signal ({})", name, if unit.is_empty() { "no unit" } else { unit });
+        let _ = writeln!(out, "# TYPE {} gauge", name);
+        for l in lines {
+            let _ = writeln!(out, "{}", l);
+        }
+    }
+    out
+}
+
+/// Name, help and value of a counter from the connection stats
+type Counter = (&'static str, &'static str, fn(&Status) -> u64);
+
+/// Internal metrics about reading from the inverters
+pub fn render_status<'a>(statuses: impl IntoIterator<Item = &'a Status>) -> String {
+    let statuses: Vec<(String, &Status)> = statuses.into_iter().map(|s| (format!("base=\"{}\"", label_value(&s.base_key)), s)).collect();
+    let mut out = String::new();
+    let counters: [Counter; 6] = [
+        ("solar_modbus_requests_total", "Modbus requests sent", |s| s.stats.requests),
+        ("solar_modbus_io_errors_total", "Modbus requests that failed with an I/O error", |s| s.stats.io_errors),
+        ("solar_modbus_exceptions_total", "Modbus requests answered with an exception", |s| s.stats.exceptions),
+        ("solar_modbus_connects_total", "Successful connections to the inverter", |s| s.stats.connects),
+        ("solar_modbus_connect_failures_total", "Failed connection attempts", |s| s.stats.connect_failures),
+        ("solar_skipped_cycles_total", "Gathering cycles skipped because of errors", |s| s.stats.skipped_cycles),
+    ];
+    for (name, help, value) in counters {
+        let _ = writeln!(out, "# HELP {} {}\n# TYPE {} counter", name, help, name);
+        for (base, status) in statuses.iter() {
+            let _ = writeln!(out, "{}{{{}}} {}", name, base, value(status));
+        }
+    }
+    let _ = writeln!(out, "# HELP solar_modbus_connected Whether the inverter connection is up\n# TYPE solar_modbus_connected gauge");
+    for (base, status) in statuses.iter() {
+        let connected = if status.state == ConnectionState::Connected { 1 } else { 0 };
+        let _ = writeln!(out, "solar_modbus_connected{{{}}} {}", base, connected);
+    }
+    let _ = writeln!(out, "# HELP solar_last_successful_cycle_timestamp_seconds Time of the last cycle that read every signal\n# TYPE solar_last_successful_cycle_timestamp_seconds gauge");
+    for (base, status) in statuses.iter() {
+        let _ = writeln!(out, "solar_last_successful_cycle_timestamp_seconds{{{}}} {}", base, status.last_cycle as f64 / 1000.0);
+    }
+    let _ = writeln!(out, "# HELP solar_read_duration_seconds Time spent on Modbus requests per category\n# TYPE solar_read_duration_seconds gauge");
+    for (base, status) in statuses.iter() {
+        for (category, duration) in status.read_durations.iter() {
+            let _ = writeln!(out, "solar_read_duration_seconds{{{},category=\"{}\"}} {}", base, category.key(), duration.as_secs_f64());
+        }
+    }
+    out
+}
+
+fn handle(stream: TcpStream, latest: &Mutex<Latest>, values: ValueMode) -> io::Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut reader = BufReader::new(stream);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    // skip the headers, nothing in there matters
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 2 {
+        line.clear();
+    }
+
+    let (status, body) = if request.starts_with("GET /metrics ") {
+        let l = latest.lock().unwrap();
+        ("200 OK", format!("{}{}", render_samples(l.batches.values(), values), render_status(l.statuses.values())))
+    } else {
+        ("404 Not Found", "Not found, try /metrics\n".to_string())
+    };
+    let stream = reader.get_mut();
+    write!(stream, "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", status, body.len(), body)?;
+    stream.flush()
+}
+
+impl PrometheusSink {
+    pub fn new(config: &PrometheusConfig) -> io::Result<PrometheusSink> {
+        let listener = TcpListener::bind(&config.listen)?;
+        info!("Serving metrics on http://{}/metrics", listener.local_addr()?);
+        let latest = Arc::new(Mutex::new(Latest::default()));
+        let shared = latest.clone();
+        let values = config.values;
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    // a slow client doesn't hold up the others
+                    Ok(s) => {
+                        let shared = shared.clone();
+                        thread::spawn(move || {
+                            if let Err(e) = handle(s, &shared, values) {
+                                debug!("Metrics request failed: {}", e);
+                            }
+                        });
+                    }
+                    Err(e) => warn!("Accepting metrics connection failed: {}", e),
+                }
+            }
+        });
+        Ok(PrometheusSink { latest })
+    }
+}
+
+#[async_trait]
+impl Sink for PrometheusSink {
+    fn name(&self) -> &str {
+        "prometheus"
+    }
+
+    async fn send(&mut self, batch: &SampleBatch) -> Result<(), SinkError> {
+        // signals are read at different intervals, keep the latest value of each
+        let mut latest = self.latest.lock().unwrap();
+        let merged = latest.batches.entry(batch.base_key.clone()).or_insert_with(|| SampleBatch { base_key: batch.base_key.clone(), samples: Vec::new() });
+        for s in batch.samples.iter() {
+            match merged.samples.iter_mut().find(|m| m.category == s.category && m.name == s.name) {
+                Some(m) => *m = s.clone(),
+                None => merged.samples.push(s.clone()),
+            }
+        }
+        Ok(())
+    }
+
+    async fn send_status(&mut self, status: &Status) -> Result<(), SinkError> {
+        self.latest.lock().unwrap().statuses.insert(status.base_key.clone(), status.clone());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::ConnectionStats;
+    use crate::sinks::test_util::batch;
+    use crate::sinks::Category;
+    use std::io::Read;
+
+    #[test]
+    fn renders_gauges_with_labels() {
+        let text = render_samples([&batch()], ValueMode::Scaled);
+        assert!(text.contains("solar_info{base=\"roof top\",general_model_ident=\"SUN2000 \\\"8KTL\\\"\"} 1"));
+        assert!(text.contains("solar_storage_soc{base=\"roof top\",pack=\"1\"} 80.5"));
+        assert!(text.contains("solar_storage_soc{base=\"roof top\"} 79"));
+        assert!(text.contains("solar_pv_voltage{base=\"roof top\",string=\"0\"} 360.5"));
+        assert_eq!(text.matches("# TYPE solar_storage_soc gauge").count(), 1);
+        assert!(!text.contains("_raw"));
+
+        let text = render_samples([&batch()], ValueMode::Both);
+        assert!(text.contains("solar_pv_voltage_raw{base=\"roof top\",string=\"0\"} 3605"));
+        assert!(text.contains("solar_pv_voltage{base=\"roof top\",string=\"0\"} 360.5"));
+    }
+
+    #[test]
+    fn lists_each_metric_once_for_all_devices() {
+        let mut cascaded = batch();
+        cascaded.base_key = "cascaded".to_string();
+        let text = render_samples([&batch(), &cascaded], ValueMode::Scaled);
+        assert_eq!(text.matches("# TYPE solar_pv_voltage gauge").count(), 1);
+        assert!(text.contains("solar_pv_voltage{base=\"roof top\",string=\"0\"} 360.5"));
+        assert!(text.contains("solar_pv_voltage{base=\"cascaded\",string=\"0\"} 360.5"));
+        assert_eq!(text.matches("solar_info{").count(), 2);
+    }
+
+    #[tokio::test]
+    async fn serves_metrics() {
+        // grab a free port for the sink
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let mut sink = PrometheusSink::new(&PrometheusConfig { enabled: true, listen: format!("127.0.0.1:{}", port), values: ValueMode::Scaled }).unwrap();
+        sink.send(&batch()).await.unwrap();
+        // a later cycle that only read some of the signals
+        let mut fast = batch();
+        fast.samples.retain(|s| s.name == "pv_0_voltage");
+        fast.samples[0].raw = PVSignalDataType::I16(3612);
+        fast.samples[0].scaled = Some(361.2);
+        sink.send(&fast).await.unwrap();
+        let mut read_durations = BTreeMap::new();
+        read_durations.insert(Category::General, Duration::from_millis(250));
+        sink.send_status(&Status {
+            base_key: "roof top".to_string(),
+            state: ConnectionState::Connected,
+            stats: ConnectionStats { io_errors: 3, ..Default::default() },
+            read_durations,
+            last_cycle: 1700000000000,
+        }).await.unwrap();
+
+        // a client that never sends its request
+        let _idle = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        stream.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("solar_pv_voltage{base=\"roof top\",string=\"0\"} 361.2"));
+        assert!(response.contains("solar_storage_soc{base=\"roof top\"} 79"));
+        assert!(response.contains("solar_modbus_io_errors_total{base=\"roof top\"} 3"));
+        assert!(response.contains("solar_read_duration_seconds{base=\"roof top\",category=\"general\"} 0.25"));
+        assert!(response.contains("solar_last_successful_cycle_timestamp_seconds{base=\"roof top\"} 1700000000"));
+    }
+}
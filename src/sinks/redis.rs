@@ -0,0 +1,272 @@
+use std::collections::{HashMap, HashSet};
+
+use async_trait::async_trait;
+use rand::prelude::*;
+use redis::aio::MultiplexedConnection;
+use redis::{RedisResult, ToRedisArgs};
+
+use crate::config::{RedisConfig, ValueMode};
+use crate::events::Event;
+use crate::parser::types::*;
+use super::{Category, Sample, SampleBatch, Sink, SinkError, Status};
+
+impl ToRedisArgs for PVSignalDataType {
+    fn write_redis_args<W>(&self, out: &mut W)
+    where
+        W: ?Sized + redis::RedisWrite,
+        Self: Sized, {
+        match self {
+            PVSignalDataType::U16(x) => x.write_redis_args(out),
+            PVSignalDataType::I16(x) => x.write_redis_args(out),
+            PVSignalDataType::U32(x) => x.write_redis_args(out),
+            PVSignalDataType::I32(x) => x.write_redis_args(out),
+            PVSignalDataType::STR(x) => x.write_redis_args(out),
+            PVSignalDataType::UNK(x) => x.write_redis_args(out),
+            PVSignalDataType::ENUM(x, _) | PVSignalDataType::BITS(x, _) => x.write_redis_args(out),
+        }
+    }
+
+    fn to_redis_args(&self) -> Vec<Vec<u8>> {
+        let mut out = Vec::new();
+        self.write_redis_args(&mut out);
+        out
+    }
+
+    fn describe_numeric_behavior(&self) -> redis::NumericBehavior {
+        redis::NumericBehavior::NonNumeric
+    }
+
+    fn is_single_arg(&self) -> bool {
+        true
+    }
+}
+
+/// Writes samples to RedisTimeSeries, one series per signal
+#[derive(Debug)]
+pub struct RedisSink {
+    client: redis::Client,
+    /// Shared by every write, opened again after it failed
+    con: Option<MultiplexedConnection>,
+    values: ValueMode,
+    events_max_len: usize,
+}
+
+fn events_key(base_key: &str) -> String {
+    format!("{}:events", base_key)
+}
+
+fn series_key(base_key: &str, sample: &Sample) -> String {
+    format!("{}:{}:{}", base_key, sample.category.key(), sample.name)
+}
+
+/// Field in the `states` hash, the general and the storage `status` differ in their category
+fn state_field(sample: &Sample) -> String {
+    format!("{}:{}", sample.category.key(), sample.name)
+}
+
+impl RedisSink {
+    /// Series written for a sample with the value that goes into each of them
+    fn series(&self, base_key: &str, sample: &Sample) -> Vec<(String, f64)> {
+        let key = series_key(base_key, sample);
+        let raw = sample.raw.as_f64().unwrap();
+        // state codes have no scaled value, they are always written as read
+        let scaled = sample.scaled.unwrap_or(raw);
+        match self.values {
+            ValueMode::Raw => vec![(key, raw)],
+            ValueMode::Scaled => vec![(key, scaled)],
+            ValueMode::Both => vec![(format!("{}:scaled", key), scaled), (key, raw)],
+        }
+    }
+}
+
+/// Value of the `data` label, PV strings are split into voltage and current
+fn data_label(sample: &Sample) -> &'static str {
+    match sample.category {
+        Category::Pv if sample.name.ends_with("voltage") => "pv_volt",
+        Category::Pv => "pv_curr",
+        c => c.key(),
+    }
+}
+
+fn is_numeric(sample: &Sample) -> bool {
+    match sample.raw {
+        PVSignalDataType::STR(_) => {
+            debug!("Skipping string: {}", sample.name);
+            false
+        },
+        PVSignalDataType::UNK(_) => {
+            warn!("Got an unknown: {}", sample.name);
+            false
+        },
+        _ => true,
+    }
+}
+
+/// Writes `secret` and reads it back
+async fn ping(con: &mut MultiplexedConnection, secret: u32) -> RedisResult<u32> {
+    let _: () = redis::cmd("SET").arg("ping").arg(secret).query_async(con).await?;
+    let result = redis::cmd("GET").arg("ping").query_async(con).await?;
+    let _: () = redis::cmd("DEL").arg("ping").query_async(con).await?;
+    Ok(result)
+}
+
+impl RedisSink {
+    pub fn new(config: &RedisConfig) -> RedisSink {
+        RedisSink {
+            client: redis::Client::open(config.url.as_str()).expect("Redis URL was validated on startup"),
+            con: None,
+            values: config.values,
+            events_max_len: config.events_max_len,
+        }
+    }
+
+    /// Latest `count` events from the stream, oldest first
+    pub async fn read_events(&self, base_key: &str, count: usize) -> Result<Vec<Event>, SinkError> {
+        let mut con = self.client.get_async_connection().await?;
+        let entries: Vec<(String, HashMap<String, String>)> = redis::cmd("XREVRANGE").arg(events_key(base_key)).arg("+").arg("-").arg("COUNT").arg(count).query_async(&mut con).await?;
+        let mut events = Vec::new();
+        for (id, fields) in entries.into_iter().rev() {
+            match Event::from_fields(&fields) {
+                Some(e) => events.push(e),
+                None => warn!("Skipping malformed event {}", id),
+            }
+        }
+        Ok(events)
+    }
+
+    /// The shared connection, opened if there is none
+    async fn connection(&mut self) -> Result<MultiplexedConnection, SinkError> {
+        if let Some(con) = &self.con {
+            return Ok(con.clone());
+        }
+        let con = self.client.get_multiplexed_async_connection().await?;
+        self.con = Some(con.clone());
+        Ok(con)
+    }
+
+    /// Drops the connection if `res` failed because of it, the next write opens a new one
+    fn check<T>(&mut self, res: RedisResult<T>) -> Result<T, SinkError> {
+        if let Err(e) = &res {
+            if e.is_io_error() || e.is_connection_dropped() || e.is_timeout() {
+                self.con = None;
+            }
+        }
+        Ok(res?)
+    }
+
+    /// Creates the missing series and adds the values of `batch`
+    async fn write_batch(&self, con: &mut MultiplexedConnection, batch: &SampleBatch) -> RedisResult<()> {
+        let base_key = &batch.base_key;
+        let numeric: Vec<&Sample> = batch.samples.iter().filter(|s| is_numeric(s)).collect();
+
+        // save data to redis using timeseries
+        // check which timeseries exist (returns Array with the key of each time series)
+        let hastimeseries: HashSet<String> = redis::cmd("TS.QUERYINDEX").arg(format!("base={}", base_key)).query_async::<_, Vec<String>>(con).await?.into_iter().collect();
+        debug!("{} Values, {} timeseries exist", numeric.len(), hastimeseries.len());
+        for s in numeric.iter() {
+            for (key, _) in self.series(base_key, s) {
+                if !hastimeseries.contains(&key) {
+                    debug!("Creating timeseries for {}", key);
+                    let _: () = redis::cmd("TS.CREATE").arg(&key).arg("LABELS").arg("base").arg(base_key).arg("type").arg("solar").arg("data").arg(data_label(s)).query_async(con).await?;
+                }
+            }
+        }
+
+        // adding a lookup-table to database if it doesnt already exist
+        debug!("Updating/adding lookup table");
+        let mut creator = redis::cmd("HSET");
+        creator.arg(format!("{}:lookup", base_key));
+        for s in batch.samples.iter() {
+            creator.arg(&s.name).arg(&s.unit);
+        }
+        let _: () = creator.query_async(con).await?;
+        // adding a gain-scaling-table to database if it doesnt already exist
+        debug!("Updating/adding scaling table");
+        let mut creator = redis::cmd("HSET");
+        creator.arg(format!("{}:scaling", base_key));
+        for s in batch.samples.iter() {
+            creator.arg(&s.name).arg(s.gain);
+        }
+        let _: () = creator.query_async(con).await?;
+        // current names of the state and bitfield registers
+        let labeled: Vec<&Sample> = batch.samples.iter().filter(|s| s.label.is_some()).collect();
+        if !labeled.is_empty() {
+            let mut creator = redis::cmd("HSET");
+            creator.arg(format!("{}:states", base_key));
+            for s in labeled {
+                creator.arg(state_field(s)).arg(s.label.as_ref());
+            }
+            let _: () = creator.query_async(con).await?;
+        }
+
+        debug!("Saving data to redis");
+
+        let mut pipe = redis::pipe();
+        for s in numeric.iter() {
+            for (key, value) in self.series(base_key, s) {
+                pipe.cmd("TS.ADD").arg(key).arg(s.time).arg(value).ignore();
+            }
+        }
+        pipe.query_async(con).await
+    }
+}
+
+#[async_trait]
+impl Sink for RedisSink {
+    fn name(&self) -> &str {
+        "redis"
+    }
+
+    async fn init(&mut self) -> Result<(), SinkError> {
+        let mut con = self.connection().await?;
+
+        // test if redis is reachable and usable
+        let secret = rand::thread_rng().gen::<u32>();
+        let res = ping(&mut con, secret).await;
+        let result = self.check(res)?;
+        if result != secret {
+            return Err(SinkError(format!("Redis returned {} instead of {}", result, secret)));
+        }
+        debug!("Redis test successful");
+        Ok(())
+    }
+
+    async fn send(&mut self, batch: &SampleBatch) -> Result<(), SinkError> {
+        let mut con = self.connection().await?;
+        let res = self.write_batch(&mut con, batch).await;
+        self.check(res)
+    }
+
+    async fn send_status(&mut self, status: &Status) -> Result<(), SinkError> {
+        let stats = &status.stats;
+        let mut con = self.connection().await?;
+        let res = redis::cmd("HSET").arg(format!("{}:connection", status.base_key))
+            .arg("state").arg(status.state.to_string())
+            .arg("connects").arg(stats.connects)
+            .arg("connect_failures").arg(stats.connect_failures)
+            .arg("requests").arg(stats.requests)
+            .arg("io_errors").arg(stats.io_errors)
+            .arg("exceptions").arg(stats.exceptions)
+            .arg("skipped_cycles").arg(stats.skipped_cycles)
+            .arg("consecutive_failures").arg(stats.consecutive_failures)
+            .arg("last_success").arg(stats.last_success)
+            .arg("last_cycle").arg(status.last_cycle)
+            .arg("last_error").arg(stats.last_error.clone().unwrap_or_default())
+            .query_async(&mut con).await;
+        self.check(res)
+    }
+
+    async fn send_events(&mut self, base_key: &str, events: &[Event]) -> Result<(), SinkError> {
+        let mut con = self.connection().await?;
+        let mut pipe = redis::pipe();
+        for e in events {
+            let cmd = pipe.cmd("XADD").arg(events_key(base_key)).arg("MAXLEN").arg("~").arg(self.events_max_len).arg("*");
+            for (k, v) in e.fields() {
+                cmd.arg(k).arg(v);
+            }
+            cmd.ignore();
+        }
+        let res = pipe.query_async(&mut con).await;
+        self.check(res)
+    }
+}
@@ -0,0 +1,140 @@
+use std::fmt;
+
+use async_trait::async_trait;
+
+use crate::connection::{Connection, ConnectionState, ConnectionStats, ModbusError};
+
+/// Where the data logger gets its registers from
+#[async_trait]
+pub trait RegisterSource: Send + fmt::Debug {
+    async fn read_holding_registers(&mut self, address: u16, count: u16) -> Result<Vec<u16>, ModbusError>;
+    async fn read_input_registers(&mut self, address: u16, count: u16) -> Result<Vec<u16>, ModbusError>;
+    /// Writes `data` starting at `address`
+    async fn write_registers(&mut self, address: u16, data: &[u16]) -> Result<(), ModbusError>;
+    fn state(&self) -> ConnectionState;
+    fn stats(&self) -> &ConnectionStats;
+    /// Records that a gathering cycle was dropped
+    fn skip_cycle(&mut self);
+}
+
+#[async_trait]
+impl RegisterSource for Connection {
+    async fn read_holding_registers(&mut self, address: u16, count: u16) -> Result<Vec<u16>, ModbusError> {
+        Connection::read_holding_registers(self, address, count).await
+    }
+
+    async fn read_input_registers(&mut self, address: u16, count: u16) -> Result<Vec<u16>, ModbusError> {
+        Connection::read_input_registers(self, address, count).await
+    }
+
+    async fn write_registers(&mut self, address: u16, data: &[u16]) -> Result<(), ModbusError> {
+        Connection::write_registers(self, address, data).await
+    }
+
+    fn state(&self) -> ConnectionState {
+        Connection::state(self)
+    }
+
+    fn stats(&self) -> &ConnectionStats {
+        Connection::stats(self)
+    }
+
+    fn skip_cycle(&mut self) {
+        Connection::skip_cycle(self)
+    }
+}
+
+/// Registers held in memory, reading anything else is an illegal address
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub struct MemorySource {
+    pub holding: std::collections::HashMap<u16, u16>,
+    pub input: std::collections::HashMap<u16, u16>,
+    stats: ConnectionStats,
+}
+
+#[cfg(test)]
+impl MemorySource {
+    /// Holding registers from `address` on
+    pub fn set(&mut self, address: u16, words: &[u16]) -> &mut MemorySource {
+        for (a, w) in (address as u32..0x10000).zip(words) {
+            self.holding.insert(a as u16, *w);
+        }
+        self
+    }
+
+    fn read(&mut self, input: bool, address: u16, count: u16) -> Result<Vec<u16>, ModbusError> {
+        self.stats.requests += 1;
+        let registers = if input { &self.input } else { &self.holding };
+        let res = (address as u32..address as u32 + count as u32)
+            .map(|a| u16::try_from(a).ok().and_then(|a| registers.get(&a).copied()))
+            .collect::<Option<Vec<u16>>>();
+        res.ok_or_else(|| self.illegal_address(if input { 4 } else { 3 }))
+    }
+
+    fn illegal_address(&mut self, function: u8) -> ModbusError {
+        self.stats.exceptions += 1;
+        ModbusError::Exception(format!("Modbus function {}: Illegal data address", function))
+    }
+}
+
+#[cfg(test)]
+#[async_trait]
+impl RegisterSource for MemorySource {
+    async fn read_holding_registers(&mut self, address: u16, count: u16) -> Result<Vec<u16>, ModbusError> {
+        self.read(false, address, count)
+    }
+
+    async fn read_input_registers(&mut self, address: u16, count: u16) -> Result<Vec<u16>, ModbusError> {
+        self.read(true, address, count)
+    }
+
+    async fn write_registers(&mut self, address: u16, data: &[u16]) -> Result<(), ModbusError> {
+        self.stats.requests += 1;
+        if address as usize + data.len() > 0x10000 {
+            return Err(self.illegal_address(16));
+        }
+        self.set(address, data);
+        Ok(())
+    }
+
+    fn state(&self) -> ConnectionState {
+        ConnectionState::Connected
+    }
+
+    fn stats(&self) -> &ConnectionStats {
+        &self.stats
+    }
+
+    fn skip_cycle(&mut self) {
+        self.stats.skipped_cycles += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn reads_and_writes_the_map() {
+        let mut map = MemorySource::default();
+        map.set(30071, &[2]).input.insert(30071, 4);
+        assert_eq!(map.read_holding_registers(30071, 1).await.unwrap(), vec![2]);
+        assert_eq!(map.read_input_registers(30071, 1).await.unwrap(), vec![4]);
+        assert!(matches!(map.read_holding_registers(30071, 2).await, Err(ModbusError::Exception(_))));
+        map.write_registers(30072, &[7, 8]).await.unwrap();
+        assert_eq!(map.read_holding_registers(30071, 3).await.unwrap(), vec![2, 7, 8]);
+        assert_eq!((map.stats().requests, map.stats().exceptions), (5, 1));
+    }
+
+    #[tokio::test]
+    async fn ends_at_the_last_register() {
+        let mut map = MemorySource::default();
+        map.set(65534, &[1, 2, 3]);
+        assert_eq!(map.holding.len(), 2);
+        assert_eq!(map.read_holding_registers(65534, 2).await.unwrap(), vec![1, 2]);
+        assert!(matches!(map.read_holding_registers(65535, 2).await, Err(ModbusError::Exception(_))));
+        assert!(matches!(map.write_registers(65535, &[4, 5]).await, Err(ModbusError::Exception(_))));
+        assert_eq!(map.read_holding_registers(65535, 1).await.unwrap(), vec![2]);
+    }
+}